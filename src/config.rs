@@ -0,0 +1,92 @@
+//! Named profiles of `config.toml`, filling in the flags not given.
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+pub const DEFAULT_PROFILE: &str = "default";
+const CONFIG_FILE: &str = "config.toml";
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// Settings of one mount, each one a command line flag of the same name.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub app_id: Option<i32>,
+    pub app_hash: Option<String>,
+    pub chat_id: Option<i64>,
+    pub mount_point: Option<PathBuf>,
+
+    pub session: Option<PathBuf>,
+    pub db: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+    pub cache_size: Option<u64>,
+    pub capacity: Option<u64>,
+
+    pub async_flush: Option<bool>,
+    pub incremental_sync: Option<bool>,
+    pub read_only: Option<bool>,
+
+    pub allow_other: Option<bool>,
+    pub auto_unmount: Option<bool>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub umask: Option<u16>,
+}
+
+/// Load `profile` from the config file at `path`, by default
+/// `$XDG_CONFIG_HOME/telegram-fuse/config.toml`.
+///
+/// Without the default file or the default profile all settings are left to
+/// the command line, any other one must exist.
+pub fn load(path: Option<&Path>, profile: &str) -> Result<Profile> {
+    let (path, required) = match path {
+        Some(path) => (path.to_owned(), true),
+        None => (config_dir()?.join(CONFIG_FILE), profile != DEFAULT_PROFILE),
+    };
+
+    let config: ConfigFile = match std::fs::read_to_string(&path) {
+        Ok(text) => {
+            toml::from_str(&text).with_context(|| format!("Failed to parse {}", path.display()))?
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound && !required => {
+            return Ok(Profile::default())
+        }
+        Err(err) => return Err(err).with_context(|| format!("Failed to read {}", path.display())),
+    };
+
+    match config.profiles.get(profile) {
+        Some(found) => Ok(found.clone()),
+        None if profile == DEFAULT_PROFILE => Ok(Profile::default()),
+        None => anyhow::bail!("Profile {} not found in {}", profile, path.display()),
+    }
+}
+
+/// `$XDG_DATA_HOME/telegram-fuse`, by default in `~/.local/share`.
+pub fn data_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// `$XDG_CONFIG_HOME/telegram-fuse`, by default in `~/.config`.
+fn config_dir() -> Result<PathBuf> {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+fn xdg_dir(var: &str, default: &str) -> Result<PathBuf> {
+    let base = match std::env::var_os(var) {
+        Some(dir) if Path::new(&dir).is_absolute() => PathBuf::from(dir),
+        _ => {
+            let home = std::env::var_os("HOME")
+                .with_context(|| format!("Neither {} nor HOME is set", var))?;
+            PathBuf::from(home).join(default)
+        }
+    };
+    Ok(base.join("telegram-fuse"))
+}
@@ -1,11 +1,13 @@
 use crate::vfs;
 
 use fuser::{
-    KernelConfig, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
-    ReplyOpen, ReplyStatfs, ReplyWrite, Request, TimeOrNow,
+    consts::FOPEN_DIRECT_IO, FileAttr, KernelConfig, ReplyAttr, ReplyCreate, ReplyData,
+    ReplyDirectory, ReplyEmpty, ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr,
+    Request, TimeOrNow,
 };
 use std::{
     ffi::OsStr,
+    path::Path,
     sync::Arc,
     time::{Duration, SystemTime},
 };
@@ -22,14 +24,43 @@ pub struct Filesystem {
     inner: Arc<FilesystemInner>,
 }
 
+/// Overrides of the attributes reported to the kernel, leaving those stored
+/// untouched.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AttrOverride {
+    /// Owner of every inode.
+    pub uid: Option<u32>,
+    /// Group of every inode.
+    pub gid: Option<u32>,
+    /// Permission bits cleared from every inode.
+    pub umask: Option<u16>,
+}
+
 struct FilesystemInner {
     vfs: Arc<vfs::Vfs>,
+    overrides: AttrOverride,
+}
+
+impl FilesystemInner {
+    fn file_attr(&self, attr: &vfs::InodeAttr) -> FileAttr {
+        let mut attr = attr.get_file_attr();
+        if let Some(uid) = self.overrides.uid {
+            attr.uid = uid;
+        }
+        if let Some(gid) = self.overrides.gid {
+            attr.gid = gid;
+        }
+        if let Some(umask) = self.overrides.umask {
+            attr.perm &= !umask;
+        }
+        attr
+    }
 }
 
 impl Filesystem {
-    pub fn new(vfs: Arc<vfs::Vfs>) -> Self {
+    pub fn new(vfs: Arc<vfs::Vfs>, overrides: AttrOverride) -> Self {
         Self {
-            inner: Arc::new(FilesystemInner { vfs }),
+            inner: Arc::new(FilesystemInner { vfs, overrides }),
         }
     }
 
@@ -74,9 +105,22 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    // TODO:
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        reply.statfs(0, 0, 0, 0, 0, BLOCK_SIZE, NAME_LEN, FRAGMENT_SIZE);
+        self.spawn(|inner| async move {
+            match inner.vfs.statfs().await {
+                Err(err) => reply.error(err.into_c_err()),
+                Ok(stat) => reply.statfs(
+                    stat.blocks,
+                    stat.free_blocks,
+                    stat.free_blocks,
+                    stat.files,
+                    stat.free_files,
+                    BLOCK_SIZE,
+                    NAME_LEN,
+                    FRAGMENT_SIZE,
+                ),
+            }
+        });
     }
 
     fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
@@ -85,7 +129,7 @@ impl fuser::Filesystem for Filesystem {
             match inner.vfs.lookup(parent, &name).await {
                 Err(err) => reply.error(err.into_c_err()),
                 Ok(attr) => {
-                    reply.entry(&TTL, &attr.get_file_attr(), GENERATION);
+                    reply.entry(&TTL, &inner.file_attr(&attr), GENERATION);
                 }
             }
         });
@@ -93,7 +137,9 @@ impl fuser::Filesystem for Filesystem {
 
     fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
         self.spawn(|inner| async move {
-            inner.vfs.forget(ino, nlookup).await.unwrap();
+            if let Err(err) = inner.vfs.forget(ino, nlookup).await {
+                log::error!("Failed to forget inode {}: {}", ino, err);
+            }
         });
     }
 
@@ -101,7 +147,7 @@ impl fuser::Filesystem for Filesystem {
         self.spawn(|inner| async move {
             match inner.vfs.get_attr(ino).await {
                 Err(err) => reply.error(err.into_c_err()),
-                Ok(attr) => reply.attr(&TTL, &attr.get_file_attr()),
+                Ok(attr) => reply.attr(&TTL, &inner.file_attr(&attr)),
             }
         });
     }
@@ -121,8 +167,10 @@ impl fuser::Filesystem for Filesystem {
 
     fn releasedir(&mut self, _req: &Request, ino: u64, fh: u64, _flags: i32, reply: ReplyEmpty) {
         self.spawn(|inner| async move {
-            inner.vfs.close_dir(ino, fh).await.unwrap();
-            reply.ok();
+            match inner.vfs.close_dir(ino, fh).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into_c_err()),
+            }
         });
     }
 
@@ -140,7 +188,7 @@ impl fuser::Filesystem for Filesystem {
                 Ok(entries) => {
                     for (idx, entry) in entries.as_ref().iter().enumerate().skip(offset as usize) {
                         if reply.add(
-                            entry.child_ino as u64,
+                            entry.child_ino,
                             (idx + 1) as i64,
                             entry.file_type,
                             &entry.name,
@@ -154,41 +202,32 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 
-    // TODO:
     fn open(&mut self, _req: &Request, ino: u64, flags: i32, reply: ReplyOpen) {
-        // Read is always allowed.
-        static_assertions::const_assert_eq!(libc::O_RDONLY, 0);
         log::trace!("open flags: {:#x}", flags);
 
-        let write = (flags & libc::O_WRONLY) != 0;
-        assert_eq!(flags & libc::O_TRUNC, 0);
-        let ret_flags = flags & libc::O_WRONLY;
-
+        let ret_flags = open_flags(flags);
         self.spawn(|inner| async move {
-            match inner.vfs.open_file(ino, write).await {
-                Ok(fh) => reply.opened(fh, ret_flags as u32),
+            match inner.vfs.open_file(ino, flags).await {
+                Ok(fh) => reply.opened(fh, ret_flags),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
     }
 
-    // TODO:
     fn create(
         &mut self,
         req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         flags: i32,
         reply: ReplyCreate,
     ) {
         log::trace!("open flags: {:#x}", flags);
 
-        let _write = (flags & libc::O_WRONLY) != 0;
-        let exclusive = (flags & libc::O_EXCL) != 0;
-        let truncate = (flags & libc::O_TRUNC) != 0;
-        let ret_flags = flags & (libc::O_WRONLY | libc::O_EXCL | libc::O_TRUNC);
+        let ret_flags = open_flags(flags);
+        let perm = permissions(mode, umask);
         let uid = req.uid();
         let gid = req.gid();
 
@@ -196,11 +235,11 @@ impl fuser::Filesystem for Filesystem {
         self.spawn(|inner| async move {
             match inner
                 .vfs
-                .open_create_file(parent, &name, uid, gid, truncate, exclusive)
+                .open_create_file(parent, &name, perm, uid, gid, flags)
                 .await
             {
-                Ok(attr) => {
-                    reply.created(&TTL, &attr.get_file_attr(), GENERATION, 0, ret_flags as u32)
+                Ok((attr, fh)) => {
+                    reply.created(&TTL, &inner.file_attr(&attr), GENERATION, fh, ret_flags)
                 }
                 Err(err) => reply.error(err.into_c_err()),
             }
@@ -254,16 +293,67 @@ impl fuser::Filesystem for Filesystem {
         req: &Request,
         parent: u64,
         name: &OsStr,
-        _mode: u32,
-        _umask: u32,
+        mode: u32,
+        umask: u32,
         reply: ReplyEntry,
     ) {
         let name = name.to_owned();
+        let perm = permissions(mode, umask);
         let uid = req.uid();
         let gid = req.gid();
         self.spawn(|inner| async move {
-            match inner.vfs.create_dir(parent, &name, uid, gid).await {
-                Ok(attr) => reply.entry(&TTL, &attr.get_file_attr(), GENERATION),
+            match inner.vfs.create_dir(parent, &name, perm, uid, gid).await {
+                Ok(attr) => reply.entry(&TTL, &inner.file_attr(&attr), GENERATION),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn symlink(
+        &mut self,
+        req: &Request,
+        parent: u64,
+        name: &OsStr,
+        link: &Path,
+        reply: ReplyEntry,
+    ) {
+        let name = name.to_owned();
+        let link = link.as_os_str().to_owned();
+        let uid = req.uid();
+        let gid = req.gid();
+        self.spawn(|inner| async move {
+            match inner
+                .vfs
+                .create_symlink(parent, &name, &link, uid, gid)
+                .await
+            {
+                Ok(attr) => reply.entry(&TTL, &inner.file_attr(&attr), GENERATION),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn readlink(&mut self, _req: &Request, ino: u64, reply: ReplyData) {
+        self.spawn(|inner| async move {
+            match inner.vfs.read_link(ino).await {
+                Ok(target) => reply.data(target.as_bytes()),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn link(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        newparent: u64,
+        newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        let newname = newname.to_owned();
+        self.spawn(|inner| async move {
+            match inner.vfs.link(ino, newparent, &newname).await {
+                Ok(attr) => reply.entry(&TTL, &inner.file_attr(&attr), GENERATION),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
@@ -335,11 +425,11 @@ impl fuser::Filesystem for Filesystem {
         &mut self,
         _req: &Request<'_>,
         ino: u64,
-        _mode: Option<u32>,
-        _uid: Option<u32>,
-        _gid: Option<u32>,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
         size: Option<u64>,
-        _atime: Option<TimeOrNow>,
+        atime: Option<TimeOrNow>,
         mtime: Option<TimeOrNow>,
         _ctime: Option<SystemTime>,
         _fh: Option<u64>,
@@ -350,12 +440,65 @@ impl fuser::Filesystem for Filesystem {
         reply: ReplyAttr,
     ) {
         self.spawn(|inner| async move {
-            let mtime = mtime.map(|time| match time {
-                TimeOrNow::SpecificTime(time) => time,
-                TimeOrNow::Now => SystemTime::now(),
-            });
-            match inner.vfs.set_attr(ino, size, mtime).await {
-                Ok(attr) => reply.attr(&TTL, &attr.get_file_attr()),
+            let set = vfs::SetAttr {
+                mode,
+                uid,
+                gid,
+                size,
+                atime: atime.map(system_time),
+                mtime: mtime.map(system_time),
+            };
+            match inner.vfs.set_attr(ino, set).await {
+                Ok(attr) => reply.attr(&TTL, &inner.file_attr(&attr)),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn setxattr(
+        &mut self,
+        _req: &Request,
+        ino: u64,
+        name: &OsStr,
+        value: &[u8],
+        flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        let name = name.to_owned();
+        let value = value.to_owned();
+        self.spawn(|inner| async move {
+            match inner.vfs.set_xattr(ino, &name, &value, flags).await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, size: u32, reply: ReplyXattr) {
+        let name = name.to_owned();
+        self.spawn(|inner| async move {
+            match inner.vfs.get_xattr(ino, &name, size).await {
+                Ok(value) => reply_xattr(reply, &value, size),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn listxattr(&mut self, _req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        self.spawn(|inner| async move {
+            match inner.vfs.list_xattr(ino, size).await {
+                Ok(names) => reply_xattr(reply, &names, size),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
+    }
+
+    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        let name = name.to_owned();
+        self.spawn(|inner| async move {
+            match inner.vfs.remove_xattr(ino, &name).await {
+                Ok(()) => reply.ok(),
                 Err(err) => reply.error(err.into_c_err()),
             }
         });
@@ -369,7 +512,12 @@ impl fuser::Filesystem for Filesystem {
         _datasync: bool,
         reply: ReplyEmpty,
     ) {
-        reply.ok();
+        self.spawn(|inner| async move {
+            match inner.vfs.sync_metadata().await {
+                Ok(()) => reply.ok(),
+                Err(err) => reply.error(err.into_c_err()),
+            }
+        });
     }
 
     fn fsync(&mut self, _req: &Request, ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
@@ -381,3 +529,33 @@ impl fuser::Filesystem for Filesystem {
         });
     }
 }
+
+/// Writable handles bypass the page cache.
+fn open_flags(flags: i32) -> u32 {
+    if flags & libc::O_ACCMODE == libc::O_RDONLY {
+        0
+    } else {
+        FOPEN_DIRECT_IO
+    }
+}
+
+fn system_time(time: TimeOrNow) -> SystemTime {
+    match time {
+        TimeOrNow::SpecificTime(time) => time,
+        TimeOrNow::Now => SystemTime::now(),
+    }
+}
+
+/// Permission bits of a new inode.
+fn permissions(mode: u32, umask: u32) -> u16 {
+    (mode & !umask & 0o7777) as u16
+}
+
+/// A zero `size` asks for the length of the data only.
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else {
+        reply.data(data);
+    }
+}
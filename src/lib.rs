@@ -0,0 +1,2 @@
+pub mod fuse_fs;
+pub mod vfs;
@@ -0,0 +1,235 @@
+//! Signing in, with secrets read from files, the environment or the terminal.
+
+use anyhow::{Context as _, Result};
+use clap::{Args, ValueEnum};
+use grammers_client::types::PasswordToken;
+use grammers_client::{Client, SignInError};
+use grammers_tl_types as tl;
+use qrcode::render::unicode::Dense1x2;
+use qrcode::QrCode;
+use std::io::{self, BufRead as _, IsTerminal as _, Write as _};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Data center a session without a user connects to.
+const DEFAULT_DC: i32 = 2;
+const QR_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const ENV_PREFIX: &str = "TELEGRAM_FUSE_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Method {
+    /// Phone number and login code, then the password if set
+    Phone,
+    /// QR code scanned by a signed in Telegram app
+    Qr,
+}
+
+/// Each secret not given by a file is taken from the environment variable
+/// `TELEGRAM_FUSE_<NAME>`, or else asked for on the terminal.
+#[derive(Debug, Args)]
+pub struct LoginArgs {
+    /// How to sign in
+    #[arg(long, value_enum, default_value_t = Method::Phone)]
+    method: Method,
+
+    /// File holding the phone number, or TELEGRAM_FUSE_PHONE
+    #[arg(long)]
+    phone_file: Option<PathBuf>,
+
+    /// File holding the login code, or TELEGRAM_FUSE_CODE
+    #[arg(long)]
+    code_file: Option<PathBuf>,
+
+    /// File holding the password, or TELEGRAM_FUSE_PASSWORD
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+}
+
+pub async fn sign_in(client: &Client, app_id: i32, app_hash: &str, args: &LoginArgs) -> Result<()> {
+    match args.method {
+        Method::Phone => phone_sign_in(client, app_id, app_hash, args).await,
+        Method::Qr => qr_sign_in(client, app_id, app_hash, args).await,
+    }
+}
+
+async fn phone_sign_in(
+    client: &Client,
+    app_id: i32,
+    app_hash: &str,
+    args: &LoginArgs,
+) -> Result<()> {
+    let phone = secret(
+        "phone",
+        args.phone_file.as_deref(),
+        "Enter your phone number (international format): ",
+    )?;
+    let token = client
+        .request_login_code(&phone, app_id, app_hash)
+        .await
+        .context("Failed to request the login code")?;
+    let code = secret(
+        "code",
+        args.code_file.as_deref(),
+        "Enter the code you received: ",
+    )?;
+
+    match client.sign_in(&token, &code).await {
+        Ok(_) => Ok(()),
+        Err(SignInError::PasswordRequired(password_token)) => {
+            check_password(client, password_token, args).await
+        }
+        Err(err) => Err(err).context("Failed to sign in"),
+    }
+}
+
+/// Show login tokens as QR codes until one is accepted by another device.
+async fn qr_sign_in(client: &Client, app_id: i32, app_hash: &str, args: &LoginArgs) -> Result<()> {
+    let home_dc = client
+        .session()
+        .get_user()
+        .map_or(DEFAULT_DC, |user| user.dc);
+    let request = tl::functions::auth::ExportLoginToken {
+        api_id: app_id,
+        api_hash: app_hash.to_owned(),
+        except_ids: Vec::new(),
+    };
+
+    let mut shown = Vec::new();
+    let (authorization, dc) = loop {
+        let token = match client.invoke(&request).await {
+            Ok(token) => token,
+            Err(err) if err.is("SESSION_PASSWORD_NEEDED") => {
+                let tl::enums::account::Password::Password(password) = client
+                    .invoke(&tl::functions::account::GetPassword {})
+                    .await
+                    .context("Failed to get the password parameters")?;
+                return check_password(client, PasswordToken::new(password), args).await;
+            }
+            Err(err) => return Err(err).context("Failed to get a login token"),
+        };
+
+        match token {
+            tl::enums::auth::LoginToken::Token(token) => {
+                if token.token != shown {
+                    print_qr(&token.token)?;
+                    shown = token.token;
+                }
+                tokio::time::sleep(QR_POLL_INTERVAL).await;
+            }
+            tl::enums::auth::LoginToken::MigrateTo(migrate) => {
+                // The account lives in another data center, finish there.
+                let request = tl::functions::auth::ImportLoginToken {
+                    token: migrate.token,
+                };
+                match client
+                    .invoke_in_dc(&request, migrate.dc_id)
+                    .await
+                    .context("Failed to import the login token")?
+                {
+                    tl::enums::auth::LoginToken::Success(success) => {
+                        break (success.authorization, migrate.dc_id)
+                    }
+                    _ => anyhow::bail!("Login token was not accepted"),
+                }
+            }
+            tl::enums::auth::LoginToken::Success(success) => {
+                break (success.authorization, home_dc)
+            }
+        }
+    };
+
+    // Record the account like the other sign in methods, so the session
+    // connects to its data center from now on.
+    let user = match authorization {
+        tl::enums::auth::Authorization::Authorization(authorization) => authorization.user,
+        tl::enums::auth::Authorization::SignUpRequired(_) => {
+            anyhow::bail!("No account signed in by the QR code")
+        }
+    };
+    match user {
+        tl::enums::User::User(user) => client.session().set_user(user.id, dc, user.bot),
+        tl::enums::User::Empty(_) => anyhow::bail!("No account signed in by the QR code"),
+    }
+
+    Ok(())
+}
+
+async fn check_password(
+    client: &Client,
+    password_token: PasswordToken,
+    args: &LoginArgs,
+) -> Result<()> {
+    let message = match password_token.hint() {
+        Some(hint) => format!("Enter the password (hint {}): ", hint),
+        None => "Enter the password: ".to_owned(),
+    };
+    let password = secret("password", args.password_file.as_deref(), &message)?;
+
+    client
+        .check_password(password_token, password)
+        .await
+        .context("Failed to check the password")?;
+    Ok(())
+}
+
+fn print_qr(token: &[u8]) -> Result<()> {
+    let url = format!(
+        "tg://login?token={}",
+        base64::encode_config(token, base64::URL_SAFE_NO_PAD)
+    );
+    let code = QrCode::new(url.as_bytes())?;
+    // Terminals draw light text on dark, so light blocks are the background.
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    println!(
+        "Scan with Telegram, in Settings > Devices > Link Desktop Device:\n{}",
+        image
+    );
+    Ok(())
+}
+
+/// Read secret `name` from `file`, or else its environment variable, or else
+/// the terminal.
+///
+/// Surrounding whitespace is dropped, except from the password where only
+/// the line break is.
+fn secret(name: &str, file: Option<&Path>, message: &str) -> Result<String> {
+    let var = format!("{}{}", ENV_PREFIX, name.to_uppercase());
+    let value = if let Some(file) = file {
+        std::fs::read_to_string(file)
+            .with_context(|| format!("Failed to read {}", file.display()))?
+    } else if let Ok(value) = std::env::var(&var) {
+        value
+    } else if io::stdin().is_terminal() {
+        prompt(message)?
+    } else {
+        anyhow::bail!(
+            "No {0} given, by --{0}-file, {1} or the terminal",
+            name,
+            var
+        );
+    };
+    let value = if name == "password" {
+        value.trim_end_matches(['\r', '\n'])
+    } else {
+        value.trim()
+    };
+    Ok(value.to_owned())
+}
+
+fn prompt(message: &str) -> Result<String> {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout.write_all(message.as_bytes())?;
+    stdout.flush()?;
+
+    let stdin = io::stdin();
+    let mut stdin = stdin.lock();
+
+    let mut line = String::new();
+    stdin.read_line(&mut line)?;
+    Ok(line)
+}
@@ -1,16 +1,20 @@
+mod config;
+mod login;
+
 use anyhow::{Context as _, Result};
-use clap::Parser;
-use fuser::MountOption;
-use grammers_client::{Client, Config, SignInError};
+use clap::{Args, Parser, Subcommand};
+use config::Profile;
+use fuser::{FileType, MountOption};
+use grammers_client::{Client, Config};
 use grammers_session::Session;
-use std::io::{self, BufRead as _, Write as _};
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use telegram_fuse::{fuse_fs, vfs};
 use tokio::task;
 
-mod fuse_fs;
-mod vfs;
-
 const SESSION_FILE: &str = "tg.session";
+const CACHE_DIR: &str = "cache";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -23,105 +27,383 @@ async fn main() -> Result<()> {
 
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
-    let args = Args::parse();
+    let cli = Cli::parse();
+    let name = cli.profile().to_owned();
+    let profile = config::load(cli.paths.config.as_deref(), &name)?;
+    let paths = cli.paths.resolve(&name, &profile)?;
+
+    match cli.command {
+        Command::Mount(args) => mount(args, profile, &paths).await,
+        Command::Login { telegram, args } => {
+            login(&telegram.resolve(&profile)?, &args, &paths).await
+        }
+        Command::Logout(args) => logout(&args.resolve(&profile)?, &paths).await,
+        Command::Status(args) => status(&args.resolve(&profile)?, &paths).await,
+        Command::Fsck => fsck(&paths.db).await,
+        Command::Ls => list(&paths.db).await,
+        Command::Export { output } => vfs::offline::export(&paths.db, &output).await,
+        Command::Import { input, force } => {
+            create_parent(&paths.db)?;
+            vfs::offline::import(&input, &paths.db, force).await
+        }
+    }
+}
+
+/// Files of one mount.
+struct Paths {
+    session: PathBuf,
+    db: PathBuf,
+    cache_dir: PathBuf,
+}
 
-    async_main(args).await
+/// API credentials of the app.
+struct Credentials {
+    app_id: i32,
+    app_hash: String,
 }
 
-async fn async_main(args: Args) -> Result<()> {
-    let app_id = args.app_id;
-    let app_hash = args.app_hash;
+impl Cli {
+    /// Profile named by `mount`, or else by `--profile`.
+    fn profile(&self) -> &str {
+        match &self.command {
+            Command::Mount(MountArgs {
+                profile: Some(profile),
+                ..
+            }) => profile,
+            _ => self
+                .paths
+                .profile
+                .as_deref()
+                .unwrap_or(config::DEFAULT_PROFILE),
+        }
+    }
+}
+
+impl PathArgs {
+    /// Fill in the paths not given with those of the profile, or else ones in
+    /// its data directory.
+    fn resolve(self, name: &str, profile: &Profile) -> Result<Paths> {
+        let profile_dir = config::data_dir()?.join(name);
+        Ok(Paths {
+            session: self
+                .session
+                .or_else(|| profile.session.clone())
+                .unwrap_or_else(|| profile_dir.join(SESSION_FILE)),
+            db: self
+                .db
+                .or_else(|| profile.db.clone())
+                .unwrap_or_else(|| profile_dir.join(vfs::DB_FILE)),
+            cache_dir: self
+                .cache_dir
+                .or_else(|| profile.cache_dir.clone())
+                .unwrap_or_else(|| profile_dir.join(CACHE_DIR)),
+        })
+    }
+}
+
+impl TelegramArgs {
+    fn resolve(self, profile: &Profile) -> Result<Credentials> {
+        Ok(Credentials {
+            app_id: self
+                .app_id
+                .or(profile.app_id)
+                .context("No app id, given by --app-id or the profile")?,
+            app_hash: self
+                .app_hash
+                .or_else(|| profile.app_hash.clone())
+                .context("No app hash, given by --app-hash or the profile")?,
+        })
+    }
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+async fn connect(credentials: &Credentials, paths: &Paths) -> Result<Client> {
+    create_parent(&paths.session)?;
 
     log::info!("Connecting to Telegram...");
     let client = Client::connect(Config {
-        session: Session::load_file_or_create(SESSION_FILE)?,
-        api_id: app_id,
-        api_hash: app_hash.clone(),
+        session: Session::load_file_or_create(&paths.session)?,
+        api_id: credentials.app_id,
+        api_hash: credentials.app_hash.clone(),
         params: Default::default(),
     })
     .await?;
     log::info!("Connected!");
+    Ok(client)
+}
 
-    if !client.is_authorized().await? {
-        log::info!("Signing in...");
-        let phone = prompt("Enter your phone number (international format): ")?;
-        let token = client.request_login_code(&phone, app_id, &app_hash).await?;
-        let code = prompt("Enter the code you received: ")?;
-        let signed_in = client.sign_in(&token, &code).await;
-        match signed_in {
-            Err(SignInError::PasswordRequired(password_token)) => {
-                let hint = password_token.hint().unwrap();
-                let prompt_message = format!("Enter the password (hint {}): ", &hint);
-                let password = prompt(prompt_message.as_str())?;
-
-                client
-                    .check_password(password_token, password.trim())
-                    .await?;
-            }
-            Ok(_) => (),
-            Err(e) => panic!("{}", e),
-        };
-        log::info!("Signed in!");
-        match client.session().save_to_file(SESSION_FILE) {
-            Ok(_) => {}
-            Err(e) => {
-                log::info!(
-                    "NOTE: failed to save the session, will sign out when done: {}",
-                    e
-                );
-            }
+async fn login(credentials: &Credentials, args: &login::LoginArgs, paths: &Paths) -> Result<()> {
+    let client = connect(credentials, paths).await?;
+    if client.is_authorized().await? {
+        log::info!("Already signed in");
+        return Ok(());
+    }
+
+    log::info!("Signing in...");
+    login::sign_in(&client, credentials.app_id, &credentials.app_hash, args).await?;
+    log::info!("Signed in!");
+    client
+        .session()
+        .save_to_file(&paths.session)
+        .context("Failed to save the session")?;
+
+    Ok(())
+}
+
+async fn logout(credentials: &Credentials, paths: &Paths) -> Result<()> {
+    let client = connect(credentials, paths).await?;
+    if client.is_authorized().await? {
+        client.sign_out().await?;
+        log::info!("Signed out!");
+    }
+    match std::fs::remove_file(&paths.session) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+async fn status(credentials: &Credentials, paths: &Paths) -> Result<()> {
+    let client = connect(credentials, paths).await?;
+    if client.is_authorized().await? {
+        let me = client.get_me().await?;
+        match me.username() {
+            Some(username) => println!("Signed in as {} (@{})", me.full_name(), username),
+            None => println!("Signed in as {}", me.full_name()),
         }
+    } else {
+        println!("Not signed in");
+    }
+
+    if paths.db.exists() {
+        let status = vfs::offline::status(&paths.db).await?;
+        println!(
+            "{}: {} inodes, {} bytes, {} changes not uploaded",
+            paths.db.display(),
+            status.inodes,
+            status.bytes,
+            status.pending
+        );
+    } else {
+        println!("{}: not created yet", paths.db.display());
+    }
+
+    Ok(())
+}
+
+async fn fsck(db: &Path) -> Result<()> {
+    let problems = vfs::offline::check(db).await?;
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{} problems found", problems.len());
+    }
+    println!("{}: ok", db.display());
+    Ok(())
+}
+
+async fn list(db: &Path) -> Result<()> {
+    for entry in vfs::offline::list(db).await? {
+        let kind = match entry.kind {
+            FileType::Directory => 'd',
+            FileType::Symlink => 'l',
+            _ => '-',
+        };
+        println!("{} {:>12} {}", kind, entry.size, entry.path);
+    }
+    Ok(())
+}
+
+/// Mount with the flags given, or else those of the profile.
+async fn mount(args: MountArgs, profile: Profile, paths: &Paths) -> Result<()> {
+    let mount_point = args
+        .mount_point
+        .or(profile.mount_point.clone())
+        .context("No mount point, given by --mount-point or the profile")?;
+    let read_only = args.read_only.or(profile.read_only).unwrap_or_default();
+
+    let client = connect(&args.telegram.resolve(&profile)?, paths).await?;
+    if !client.is_authorized().await? {
+        anyhow::bail!("Not signed in, run `telegram-fuse login` first");
+    }
+    // Sessions of bots may come from elsewhere, they cannot list chats or
+    // search messages.
+    if client.session().get_user().is_some_and(|user| user.bot) {
+        anyhow::bail!("Signed in as a bot, which cannot be used, sign in as a user");
     }
 
     let client_handle = client.clone();
     task::spawn(async move { client.run_until_disconnected().await });
 
-    let async_flush = match args.async_flush {
-        Some(arg) => arg,
-        None => false,
+    let mut config = vfs::Config {
+        async_flush: args.async_flush.or(profile.async_flush).unwrap_or_default(),
+        incremental_sync: args
+            .incremental_sync
+            .or(profile.incremental_sync)
+            .unwrap_or(true),
+        read_only,
+        db_path: paths.db.clone(),
+        cache_dir: paths.cache_dir.clone(),
+        ..Default::default()
     };
-    let vfs = vfs::Vfs::new(client_handle, args.chat_id, async_flush)
+    create_parent(&config.db_path)?;
+    if let Some(cache_size) = args.cache_size.or(profile.cache_size) {
+        config.cache_size = cache_size;
+    }
+    if let Some(capacity) = args.capacity.or(profile.capacity) {
+        config.capacity = capacity;
+    }
+    let store = vfs::TelegramStore::new(client_handle, args.chat_id.or(profile.chat_id))
+        .await
+        .context("Failed to open chat")?;
+    let vfs = vfs::Vfs::new(Arc::new(store), config)
         .await
         .context("Failed to initialize vfs")?;
 
     log::info!("Mounting...");
-    let fs = fuse_fs::Filesystem::new(vfs);
-    let fuse_options = [
+    let overrides = fuse_fs::AttrOverride {
+        uid: args.uid.or(profile.uid),
+        gid: args.gid.or(profile.gid),
+        umask: args.umask.or(profile.umask),
+    };
+    let fs = fuse_fs::Filesystem::new(vfs, overrides);
+    let mut fuse_options = vec![
         MountOption::FSName("telegram".into()),
         MountOption::DefaultPermissions,
         MountOption::NoDev,
         MountOption::NoSuid,
         MountOption::NoAtime,
-        MountOption::RW,
+        if read_only {
+            MountOption::RO
+        } else {
+            MountOption::RW
+        },
     ];
+    if args.allow_other.or(profile.allow_other).unwrap_or_default() {
+        fuse_options.push(MountOption::AllowOther);
+    }
+    if args
+        .auto_unmount
+        .or(profile.auto_unmount)
+        .unwrap_or_default()
+    {
+        fuse_options.push(MountOption::AutoUnmount);
+    }
 
-    tokio::task::spawn_blocking(move || fuser::mount2(fs, &args.mount_point, &fuse_options))
-        .await??;
+    tokio::task::spawn_blocking(move || fuser::mount2(fs, &mount_point, &fuse_options)).await??;
 
     Ok(())
 }
 
-fn prompt(message: &str) -> Result<String> {
-    let stdout = io::stdout();
-    let mut stdout = stdout.lock();
-    stdout.write_all(message.as_bytes())?;
-    stdout.flush()?;
+fn parse_umask(value: &str) -> std::result::Result<u16, String> {
+    match u16::from_str_radix(value, 8) {
+        Ok(umask) if umask <= 0o7777 => Ok(umask),
+        _ => Err(format!("invalid octal umask: {}", value)),
+    }
+}
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+struct Cli {
+    #[command(flatten)]
+    paths: PathArgs,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Args)]
+struct PathArgs {
+    /// Config file of the profiles [default:
+    /// ~/.config/telegram-fuse/config.toml]
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
+    /// Profile of the config file, whose data directory,
+    /// ~/.local/share/telegram-fuse/<profile>, holds the files not given below
+    /// [default: default]
+    #[arg(long, global = true)]
+    profile: Option<String>,
+
+    /// Session file of the signed in account
+    #[arg(long, global = true)]
+    session: Option<PathBuf>,
 
-    let stdin = io::stdin();
-    let mut stdin = stdin.lock();
+    /// Metadata database, each mount needs its own
+    #[arg(long, global = true)]
+    db: Option<PathBuf>,
 
-    let mut line = String::new();
-    stdin.read_line(&mut line)?;
-    Ok(line)
+    /// Directory of cached file contents
+    #[arg(long, global = true)]
+    cache_dir: Option<PathBuf>,
 }
 
-#[derive(Debug, Parser)]
-struct Args {
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Mount the chat, once signed in
+    Mount(MountArgs),
+
+    /// Sign in and save the session
+    Login {
+        #[command(flatten)]
+        telegram: TelegramArgs,
+
+        #[command(flatten)]
+        args: login::LoginArgs,
+    },
+
+    /// Sign out, revoking the saved session
+    Logout(TelegramArgs),
+
+    /// Show the signed in account and the state of the metadata database
+    Status(TelegramArgs),
+
+    /// Check a metadata database for corruption, while not mounted
+    Fsck,
+
+    /// List the files of a metadata database, while not mounted
+    Ls,
+
+    /// Copy a metadata database to a new file, while not mounted
+    Export { output: PathBuf },
+
+    /// Replace a metadata database with a checked copy, while not mounted
+    Import {
+        /// Replace an existing database
+        #[arg(long)]
+        force: bool,
+
+        input: PathBuf,
+    },
+}
+
+#[derive(Debug, Args)]
+struct TelegramArgs {
     #[arg(long)]
-    app_id: i32,
+    app_id: Option<i32>,
 
     #[arg(long)]
-    app_hash: String,
+    app_hash: Option<String>,
+}
+
+/// Flags not given are taken from the profile.
+#[derive(Debug, Args)]
+struct MountArgs {
+    /// Profile to mount, instead of the one of --profile
+    profile: Option<String>,
+
+    #[command(flatten)]
+    telegram: TelegramArgs,
+
+    #[arg(long)]
+    mount_point: Option<PathBuf>,
 
     #[arg(long)]
     chat_id: Option<i64>,
@@ -129,5 +411,41 @@ struct Args {
     #[arg(long)]
     async_flush: Option<bool>,
 
-    mount_point: PathBuf,
+    /// Bytes of file contents to keep cached
+    #[arg(long)]
+    cache_size: Option<u64>,
+
+    /// Bytes of total space reported by df
+    #[arg(long)]
+    capacity: Option<u64>,
+
+    /// Upload metadata changes as small change logs within a minute, instead
+    /// of the whole database every 5 minutes
+    #[arg(long)]
+    incremental_sync: Option<bool>,
+
+    /// Mount read-only, never uploading or editing any message
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    read_only: Option<bool>,
+
+    /// Let users other than the one mounting access the filesystem, which
+    /// needs `user_allow_other` in /etc/fuse.conf unless mounting as root
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    allow_other: Option<bool>,
+
+    /// Unmount when the process exits, even if it crashed
+    #[arg(long, num_args = 0..=1, require_equals = true, default_missing_value = "true")]
+    auto_unmount: Option<bool>,
+
+    /// Owner reported for every file, instead of the one that created it
+    #[arg(long)]
+    uid: Option<u32>,
+
+    /// Group reported for every file, instead of the one that created it
+    #[arg(long)]
+    gid: Option<u32>,
+
+    /// Octal permission bits cleared from every file, e.g. 022
+    #[arg(long, value_parser = parse_umask)]
+    umask: Option<u16>,
 }
@@ -0,0 +1,1160 @@
+use crate::vfs::retry::RetryPolicy;
+use crate::vfs::store::{BlobInfo, RemoteStore};
+use crate::vfs::{Error, Result};
+
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection};
+use sqlx::{Connection, Pool, Row, Sqlite, Transaction};
+use std::collections::{BTreeSet, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex as SyncMutex};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tokio::time::{self, Instant, MissedTickBehavior};
+
+pub const DB_FILE: &str = "fuse.db";
+const DB_TITLE: &str = "telegram-fuse db";
+const LOG_TITLE: &str = "telegram-fuse db log";
+const LOG_EXTENSION: &str = "log";
+/// Seconds without further changes after which they are uploaded as a
+/// change log.
+const LOG_INTERVAL: u64 = 2;
+/// Seconds changes are held back at most while more keep being made.
+const LOG_MAX_DELAY: u64 = 60;
+/// Seconds between syncs uploading snapshots only.
+const SNAPSHOT_INTERVAL: u64 = 300;
+/// Seconds between checks for changes from other devices.
+const POLL_INTERVAL: u64 = 30;
+
+/// Tables replicated through the change log: name, key columns, other columns.
+///
+/// A migration changing the columns of these tables must recreate the
+/// triggers.
+const LOGGED_TABLES: &[(&str, &[&str], &[&str])] = &[
+    (
+        "node",
+        &["ino"],
+        &[
+            "size",
+            "blocks",
+            "atime",
+            "mtime",
+            "ctime",
+            "crtime",
+            "kind",
+            "perm",
+            "nlink",
+            "uid",
+            "gid",
+            "rdev",
+            "blksize",
+            "flags",
+            "remote_id",
+            "chunk_size",
+            "target",
+        ],
+    ),
+    (
+        "node_tree",
+        &["parent_ino", "name"],
+        &["child_ino", "file_type"],
+    ),
+    ("chunk", &["ino", "idx"], &["remote_id"]),
+    ("xattr", &["ino", "name"], &["value"]),
+];
+
+/// Create the change log, filled by triggers with an image of every row
+/// changed in the logged tables, see `Change`.
+///
+/// Entries are numbered by an increasing sequence number, which is also how
+/// far a copy of the database is: a snapshot holds every change up to the
+/// last number it allocated. A row changed again replaces its entry, under a
+/// new number, so a row written over and over is uploaded once.
+pub async fn create_tables(tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+    // Entries replayed from other devices have no key, they are only kept
+    // until the end of the replay.
+    let sql = "
+        CREATE TABLE change_log (
+            seq INTEGER PRIMARY KEY AUTOINCREMENT,
+            key TEXT UNIQUE,
+            change TEXT NOT NULL
+        )
+    ";
+    sqlx::query(sql).execute(&mut *tx).await?;
+
+    // Single row, changes up to this number are in the remote store, and
+    // the commit holding the last of them.
+    let sql = "
+        CREATE TABLE sync_state (
+            id INTEGER PRIMARY KEY CHECK (id = 0),
+            synced_seq INTEGER NOT NULL,
+            synced_id INTEGER NOT NULL
+        )
+    ";
+    sqlx::query(sql).execute(&mut *tx).await?;
+    sqlx::query("INSERT INTO sync_state (id, synced_seq, synced_id) VALUES (0, 0, 0)")
+        .execute(&mut *tx)
+        .await?;
+
+    create_triggers(tx).await
+}
+
+async fn create_triggers(tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+    for (table, keys, columns) in LOGGED_TABLES {
+        let names = keys.iter().chain(*columns).copied().collect::<Vec<_>>();
+        let upsert = change_expr(table, "upsert", &names, "NEW");
+        let delete = change_expr(table, "delete", keys, "OLD");
+        let new_key = row_key(table, keys, "NEW");
+        let old_key = row_key(table, keys, "OLD");
+        let rekeyed = keys
+            .iter()
+            .map(|key| format!("OLD.{key} IS NOT NEW.{key}"))
+            .collect::<Vec<_>>()
+            .join(" OR ");
+
+        let sql = format!(
+            "
+            CREATE TRIGGER {table}_insert AFTER INSERT ON {table}
+            BEGIN
+                INSERT OR REPLACE INTO change_log (key, change) VALUES ({new_key}, {upsert});
+            END
+            "
+        );
+        sqlx::query(&sql).execute(&mut *tx).await?;
+
+        // A changed key leaves the old row behind unless it is deleted.
+        let sql = format!(
+            "
+            CREATE TRIGGER {table}_update AFTER UPDATE ON {table}
+            BEGIN
+                INSERT OR REPLACE INTO change_log (key, change)
+                    SELECT {old_key}, {delete} WHERE {rekeyed};
+                INSERT OR REPLACE INTO change_log (key, change) VALUES ({new_key}, {upsert});
+            END
+            "
+        );
+        sqlx::query(&sql).execute(&mut *tx).await?;
+
+        let sql = format!(
+            "
+            CREATE TRIGGER {table}_delete AFTER DELETE ON {table}
+            BEGIN
+                INSERT OR REPLACE INTO change_log (key, change) VALUES ({old_key}, {delete});
+            END
+            "
+        );
+        sqlx::query(&sql).execute(&mut *tx).await?;
+    }
+
+    Ok(())
+}
+
+async fn drop_triggers(tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+    for (table, _, _) in LOGGED_TABLES {
+        for event in ["insert", "update", "delete"] {
+            sqlx::query(&format!("DROP TRIGGER {table}_{event}"))
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// SQL expression naming the `row` (`NEW` or `OLD`) among all logged rows.
+fn row_key(table: &str, keys: &[&str], row: &str) -> String {
+    let values = keys
+        .iter()
+        .map(|key| format!("quote({row}.{key})"))
+        .collect::<Vec<_>>()
+        .join(" || ',' || ");
+    format!("'{table}:' || {values}")
+}
+
+/// SQL expression building the text of a `Change` to the `row` (`NEW` or
+/// `OLD`) with the values of `names`.
+fn change_expr(table: &str, op: &str, names: &[&str], row: &str) -> String {
+    let values = names
+        .iter()
+        .map(|name| format!("quote({row}.{name})"))
+        .collect::<Vec<_>>()
+        .join(" || ',' || ");
+    format!("'{table} {op} ' || {values}")
+}
+
+/// Column value of a `Change`.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Value {
+    /// The literal `quote()` writes for the value.
+    fn quote(&self) -> String {
+        match self {
+            Self::Null => "NULL".to_owned(),
+            Self::Integer(v) => v.to_string(),
+            Self::Real(v) => format!("{:?}", v),
+            Self::Text(v) => format!("'{}'", v.replace('\'', "''")),
+            Self::Blob(v) => {
+                let hex = v.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+                format!("X'{}'", hex)
+            }
+        }
+    }
+}
+
+/// A row written or deleted, as `<table> upsert <values>` with the values of
+/// all its columns, or `<table> delete <values>` with those of its key.
+///
+/// Values are SQL literals as written by `quote()`. They are parsed, and
+/// never run as SQL: a change log from the chat can only change rows of the
+/// logged tables.
+#[derive(Debug, Clone, PartialEq)]
+struct Change {
+    table: &'static str,
+    delete: bool,
+    values: Vec<Value>,
+}
+
+impl Change {
+    fn parse(text: &str) -> Result<Self> {
+        let mut parts = text.splitn(3, ' ');
+        let (table, op, values) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(table), Some(op), Some(values)) => (table, op, values),
+            _ => return Err(Error::MalformedLog),
+        };
+        let (table, keys, columns) = LOGGED_TABLES
+            .iter()
+            .find(|(name, _, _)| *name == table)
+            .ok_or(Error::MalformedLog)?;
+        let (delete, count) = match op {
+            "upsert" => (false, keys.len() + columns.len()),
+            "delete" => (true, keys.len()),
+            _ => return Err(Error::MalformedLog),
+        };
+        let values = parse_values(values)?;
+        if values.len() != count {
+            return Err(Error::MalformedLog);
+        }
+
+        Ok(Self {
+            table,
+            delete,
+            values,
+        })
+    }
+
+    /// Name of the row among all logged rows, as `row_key` builds it.
+    fn key(&self) -> String {
+        let (_, keys, _) = LOGGED_TABLES
+            .iter()
+            .find(|(name, _, _)| *name == self.table)
+            .expect("table checked by parse");
+        let values = self.values[..keys.len()]
+            .iter()
+            .map(Value::quote)
+            .collect::<Vec<_>>()
+            .join(",");
+        format!("{}:{}", self.table, values)
+    }
+
+    /// Inode whose content may have changed, that of a node or chunk row.
+    fn content_ino(&self) -> Option<i64> {
+        match (self.table, self.values.first()) {
+            ("node" | "chunk", Some(Value::Integer(ino))) => Some(*ino),
+            _ => None,
+        }
+    }
+
+    /// Redo the change within `tx`.
+    async fn apply(&self, tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let (table, keys, columns) = LOGGED_TABLES
+            .iter()
+            .find(|(name, _, _)| *name == self.table)
+            .ok_or(Error::MalformedLog)?;
+        let sql = if self.delete {
+            let condition = keys
+                .iter()
+                .enumerate()
+                .map(|(idx, key)| format!("{}=${}", key, idx + 1))
+                .collect::<Vec<_>>()
+                .join(" AND ");
+            format!("DELETE FROM {table} WHERE {condition}")
+        } else {
+            let names = keys.iter().chain(*columns).copied().collect::<Vec<_>>();
+            let params = (1..=names.len())
+                .map(|idx| format!("${}", idx))
+                .collect::<Vec<_>>()
+                .join(", ");
+            format!(
+                "INSERT OR REPLACE INTO {table} ({}) VALUES ({params})",
+                names.join(", ")
+            )
+        };
+
+        let mut query = sqlx::query(&sql);
+        for value in &self.values {
+            query = match value {
+                Value::Null => query.bind(None::<i64>),
+                Value::Integer(v) => query.bind(*v),
+                Value::Real(v) => query.bind(*v),
+                Value::Text(v) => query.bind(v.as_str()),
+                Value::Blob(v) => query.bind(v.as_slice()),
+            };
+        }
+        query.execute(&mut *tx).await?;
+
+        Ok(())
+    }
+}
+
+/// Parse the comma-separated literals written by `quote()`.
+fn parse_values(mut text: &str) -> Result<Vec<Value>> {
+    let mut values = Vec::new();
+    loop {
+        let (value, rest) = parse_value(text)?;
+        values.push(value);
+        match rest.strip_prefix(',') {
+            Some(rest) => text = rest,
+            None if rest.is_empty() => return Ok(values),
+            None => return Err(Error::MalformedLog),
+        }
+    }
+}
+
+fn parse_value(text: &str) -> Result<(Value, &str)> {
+    if let Some(rest) = text.strip_prefix("NULL") {
+        return Ok((Value::Null, rest));
+    }
+    if let Some(rest) = text.strip_prefix("X'") {
+        let end = rest.find('\'').ok_or(Error::MalformedLog)?;
+        let hex = &rest[..end];
+        if hex.len() % 2 != 0 {
+            return Err(Error::MalformedLog);
+        }
+        let blob = (0..hex.len())
+            .step_by(2)
+            .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|_| Error::MalformedLog)?;
+        return Ok((Value::Blob(blob), &rest[end + 1..]));
+    }
+    if let Some(rest) = text.strip_prefix('\'') {
+        // Quotes inside are doubled.
+        let mut value = String::new();
+        let mut chars = rest.char_indices().peekable();
+        while let Some((idx, c)) = chars.next() {
+            if c != '\'' {
+                value.push(c);
+            } else if let Some((_, '\'')) = chars.peek() {
+                value.push('\'');
+                chars.next();
+            } else {
+                return Ok((Value::Text(value), &rest[idx + 1..]));
+            }
+        }
+        return Err(Error::MalformedLog);
+    }
+
+    let end = text.find(',').unwrap_or(text.len());
+    let (number, rest) = text.split_at(end);
+    if let Ok(v) = number.parse() {
+        Ok((Value::Integer(v), rest))
+    } else if number
+        .chars()
+        .all(|c| c.is_ascii_digit() || "+-.eE".contains(c))
+    {
+        let v = number.parse().map_err(|_| Error::MalformedLog)?;
+        Ok((Value::Real(v), rest))
+    } else {
+        Err(Error::MalformedLog)
+    }
+}
+
+/// Serialize log entries as `<seq> <length>\n<change>\n` records.
+fn encode(entries: &[(i64, String)]) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (seq, change) in entries {
+        buf.extend_from_slice(format!("{} {}\n", seq, change.len()).as_bytes());
+        buf.extend_from_slice(change.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+fn decode(mut buf: &[u8]) -> Result<Vec<(i64, String)>> {
+    let mut entries = Vec::new();
+    while !buf.is_empty() {
+        let end = buf
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or(Error::MalformedLog)?;
+        let header = std::str::from_utf8(&buf[..end]).map_err(|_| Error::MalformedLog)?;
+        let (seq, len) = header.split_once(' ').ok_or(Error::MalformedLog)?;
+        let seq: i64 = seq.parse().map_err(|_| Error::MalformedLog)?;
+        let len: usize = len.parse().map_err(|_| Error::MalformedLog)?;
+
+        buf = &buf[end + 1..];
+        if buf.len() <= len || buf[len] != b'\n' {
+            return Err(Error::MalformedLog);
+        }
+        let change = String::from_utf8(buf[..len].to_vec()).map_err(|_| Error::MalformedLog)?;
+        entries.push((seq, change));
+        buf = &buf[len + 1..];
+    }
+    Ok(entries)
+}
+
+/// Changes up to sequence number `seq` uploaded by one sync, as a whole
+/// snapshot or a change log.
+///
+/// Commits are told apart by their message id: devices uploading from the
+/// same commit number their changes alike.
+#[derive(Debug, Clone)]
+struct Commit {
+    /// Id of the commit it follows, 0 for none.
+    base: i32,
+    seq: i64,
+    snapshot: bool,
+    info: BlobInfo,
+}
+
+impl Commit {
+    /// Parse the name of a snapshot, `fuse.db.<base>-<seq>`, or of a change
+    /// log, `fuse.db.<base>-<seq>.log`, where `base` is the id of the commit
+    /// it follows.
+    ///
+    /// Snapshots uploaded before change tracking are named `fuse.db`, and are
+    /// as old as any tracked database.
+    fn parse(info: BlobInfo, snapshot: bool) -> Option<Self> {
+        let (base, seq) = if snapshot && info.name == DB_FILE {
+            (0, 0)
+        } else {
+            let mut range = info.name.strip_prefix(DB_FILE)?.strip_prefix('.')?;
+            if !snapshot {
+                range = range.strip_suffix(LOG_EXTENSION)?.strip_suffix('.')?;
+            }
+            let (base, seq) = range.split_once('-')?;
+            (base.parse().ok()?, seq.parse().ok()?)
+        };
+        Some(Self {
+            base,
+            seq,
+            snapshot,
+            info,
+        })
+    }
+
+    fn name(base: i32, seq: i64, snapshot: bool) -> String {
+        if snapshot {
+            format!("{}.{}-{}", DB_FILE, base, seq)
+        } else {
+            format!("{}.{}-{}.{}", DB_FILE, base, seq, LOG_EXTENSION)
+        }
+    }
+}
+
+/// All remote snapshots and change logs, oldest first.
+async fn remote_commits(store: &dyn RemoteStore) -> Result<Vec<Commit>> {
+    let snapshots = store.list(DB_TITLE).await?.into_iter().map(|i| (i, true));
+    let logs = store.list(LOG_TITLE).await?.into_iter().map(|i| (i, false));
+    let mut commits = snapshots
+        .chain(logs)
+        .filter_map(|(info, snapshot)| Commit::parse(info, snapshot))
+        .collect::<Vec<_>>();
+    commits.sort_by_key(|commit| commit.info.id);
+
+    Ok(commits)
+}
+
+/// The remote history: the latest snapshot, if any, and the change logs
+/// following it.
+///
+/// Every commit must follow the one before, so of concurrent uploads from the
+/// same base only the first one counts. The others lost the race and are
+/// deleted by their device, or left over if it crashed. So are commits older
+/// than a snapshot, which are deleted oldest first.
+fn history(commits: &[Commit]) -> Vec<Commit> {
+    let mut history: Vec<Commit> = Vec::new();
+    for commit in commits {
+        let follows = match history.last() {
+            Some(last) => commit.base == last.info.id,
+            None => commit.snapshot || commit.base == 0,
+        };
+        if follows {
+            if commit.snapshot {
+                history.clear();
+            }
+            history.push(commit.clone());
+        }
+    }
+    history
+}
+
+/// Index of the first commit of `history` after the commit `id`, or `None`
+/// if the history does not go through it.
+fn position(history: &[Commit], id: i32) -> Option<usize> {
+    match history.first() {
+        None if id == 0 => Some(0),
+        Some(first) if !first.snapshot && id == 0 => Some(0),
+        _ => history
+            .iter()
+            .position(|commit| commit.info.id == id)
+            .map(|idx| idx + 1),
+    }
+}
+
+/// Whole content of the blob `id`.
+async fn fetch_all(store: &dyn RemoteStore, id: i32) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut reader = store.fetch(id).await?;
+    while let Some(chunk) = reader.next().await? {
+        data.extend_from_slice(&chunk);
+    }
+
+    Ok(data)
+}
+
+async fn download(store: &dyn RemoteStore, id: i32, path: &Path) -> Result<()> {
+    let mut reader = store.fetch(id).await?;
+    let mut file = tokio::fs::File::create(path).await?;
+    while let Some(chunk) = reader.next().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.sync_all().await?;
+
+    Ok(())
+}
+
+/// How far a local database is.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct LocalState {
+    /// Last sequence number allocated.
+    pub last: i64,
+    /// Last sequence number uploaded.
+    pub synced: i64,
+    /// Id of the remote commit holding the changes up to `synced`, 0 for none.
+    pub synced_id: i32,
+}
+
+/// State of the database at `path`, or `None` if there is none or it tracks
+/// no changes.
+pub(crate) async fn local_state(path: &Path) -> Result<Option<LocalState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let options = SqliteConnectOptions::new().filename(path);
+    let mut conn = SqliteConnection::connect_with(&options).await?;
+    let tracked: bool =
+        sqlx::query_scalar("SELECT COUNT(*) > 0 FROM sqlite_master WHERE name='sync_state'")
+            .fetch_one(&mut conn)
+            .await?;
+    let state = if tracked {
+        let last: Option<i64> =
+            sqlx::query_scalar("SELECT seq FROM sqlite_sequence WHERE name='change_log'")
+                .fetch_optional(&mut conn)
+                .await?;
+        let (synced, synced_id) = sqlx::query_as("SELECT synced_seq, synced_id FROM sync_state")
+            .fetch_one(&mut conn)
+            .await?;
+        Some(LocalState {
+            last: last.unwrap_or_default(),
+            synced,
+            synced_id,
+        })
+    } else {
+        None
+    };
+    conn.close().await?;
+
+    Ok(state)
+}
+
+/// Keeps the remote copy of the metadata database up to date, shared by all
+/// devices mounting the same chat.
+///
+/// The remote copy is a snapshot of the whole database, followed in
+/// incremental mode by change-log messages holding the entries made since.
+/// Every sync uploads nothing if the change log has no new entries.
+///
+/// Uploads are compare-and-swap: they only follow the latest remote commit,
+/// and are withdrawn if another device got one in first. Changes from other
+/// devices are applied before local ones not uploaded yet, unless both
+/// changed the same row: that is a conflict, local changes are kept back and
+/// no further changes are accepted.
+pub struct MetadataSync {
+    db: Pool<Sqlite>,
+    db_path: PathBuf,
+    store: Arc<dyn RemoteStore>,
+    incremental: bool,
+    /// Only pick up remote changes, never upload or delete anything.
+    read_only: bool,
+    retry: RetryPolicy,
+    /// Total size of the change-log messages uploaded after the snapshot,
+    /// also serializing syncs.
+    logged: Mutex<u64>,
+    /// Local changes conflict with remote ones and can never be uploaded.
+    diverged: AtomicBool,
+    /// Chunks of files changed by other devices, whose cached content may be
+    /// out of date.
+    stale: SyncMutex<Vec<i32>>,
+}
+
+impl MetadataSync {
+    /// Bring the database at `db_path` up to the remote snapshot, returning
+    /// the id of the snapshot it was replaced with, if it was.
+    ///
+    /// A local database found in the remote history is kept, and the
+    /// remaining changes are applied by `replay`, before its own changes not
+    /// uploaded yet. One with such changes is never replaced: if a snapshot
+    /// has replaced the commits they follow, opening fails.
+    pub async fn fetch_snapshot(
+        store: &dyn RemoteStore,
+        db_path: &Path,
+        retry: &RetryPolicy,
+    ) -> anyhow::Result<Option<i32>> {
+        let commits = retry
+            .run("list metadata commits", || remote_commits(store))
+            .await?;
+        let history = history(&commits);
+
+        if let Some(state) = local_state(db_path).await? {
+            if position(&history, state.synced_id).is_some() {
+                return Ok(None);
+            }
+            if state.last > state.synced && !history.is_empty() {
+                anyhow::bail!(
+                    "Local metadata database has changes not uploaded, \
+                     but the remote one has been replaced since"
+                );
+            }
+        }
+
+        match history.first() {
+            None => Ok(None),
+            Some(first) if first.snapshot => {
+                // Replace the database at once, a partial download is never used.
+                let path = db_path.with_extension("download");
+                retry
+                    .run("download snapshot", || {
+                        download(store, first.info.id, &path)
+                    })
+                    .await?;
+                tokio::fs::rename(&path, db_path).await?;
+                log::info!("Download {} from Telegram", first.info.name);
+                Ok(Some(first.info.id))
+            }
+            Some(_) => {
+                // The history starts with the first change log, rebuild from it.
+                let _ = tokio::fs::remove_file(db_path).await;
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn new(
+        db: Pool<Sqlite>,
+        db_path: &Path,
+        store: Arc<dyn RemoteStore>,
+        incremental: bool,
+        read_only: bool,
+        retry: RetryPolicy,
+    ) -> Self {
+        Self {
+            db,
+            db_path: db_path.to_owned(),
+            store,
+            incremental,
+            read_only,
+            retry,
+            logged: Mutex::new(0),
+            diverged: AtomicBool::new(false),
+            stale: SyncMutex::new(Vec::new()),
+        }
+    }
+
+    /// Whether local changes conflict with remote ones, in which case no
+    /// further changes must be made.
+    pub fn is_diverged(&self) -> bool {
+        self.diverged.load(Ordering::Relaxed)
+    }
+
+    /// Take the chunks changed by other devices since the last call.
+    pub fn take_stale(&self) -> Vec<i32> {
+        std::mem::take(&mut *self.stale.lock().unwrap())
+    }
+
+    /// Apply the change-log messages newer than the database, which has just
+    /// been replaced by the remote snapshot `snapshot`, if any.
+    pub async fn replay(&self, snapshot: Option<i32>) -> Result<()> {
+        let mut logged = self.logged.lock().await;
+
+        if let Some(id) = snapshot {
+            // Entries pending when the snapshot was taken are in it already.
+            let mut tx = self.db.begin().await?;
+            Self::mark_synced(&mut tx, self.last_seq().await?, id).await?;
+            tx.commit().await?;
+        }
+
+        let history = history(&self.commits().await?);
+        let result = self.pull(&mut logged, &history).await;
+        self.check_conflict(result)
+    }
+
+    /// Upload changes periodically, in incremental mode once no more changes
+    /// have been made for a few seconds, and check for changes from other
+    /// devices in between.
+    ///
+    /// Stops at the first conflict, see `is_diverged`.
+    pub async fn run(self: Arc<Self>) {
+        let period = Duration::from_secs(if self.incremental {
+            LOG_INTERVAL
+        } else {
+            SNAPSHOT_INTERVAL
+        });
+        let mut upload = time::interval_at(Instant::now() + period, period);
+        upload.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        let period = Duration::from_secs(POLL_INTERVAL);
+        let mut poll = time::interval_at(Instant::now() + period, period);
+        poll.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // Last sequence number seen, and since when changes are held back.
+        let mut seen = 0;
+        let mut held = None;
+        loop {
+            let result = tokio::select! {
+                _ = upload.tick() => match self.last_seq().await {
+                    Ok(last) if self.incremental && last != seen => {
+                        seen = last;
+                        let since = *held.get_or_insert_with(Instant::now);
+                        if since.elapsed() < Duration::from_secs(LOG_MAX_DELAY) {
+                            continue;
+                        }
+                        held = None;
+                        self.upload().await
+                    }
+                    Ok(_) => {
+                        held = None;
+                        self.upload().await
+                    }
+                    Err(err) => Err(err),
+                },
+                _ = poll.tick() => self.poll().await,
+            };
+            match result {
+                Ok(()) => {}
+                Err(Error::Conflict) => {
+                    log::error!(
+                        "{} conflicts with changes from another device, refusing changes",
+                        DB_FILE
+                    );
+                    return;
+                }
+                Err(err) => log::error!("Failed to sync {}: {}", DB_FILE, err),
+            }
+        }
+    }
+
+    /// Upload changes made since the last sync, then apply changes from
+    /// other devices.
+    pub async fn sync(&self) -> Result<()> {
+        self.upload().await?;
+        self.poll().await
+    }
+
+    /// Apply changes from other devices, unless there are local changes to
+    /// upload first.
+    pub async fn poll(&self) -> Result<()> {
+        let mut logged = self.logged.lock().await;
+
+        let (synced, _) = self.sync_state().await?;
+        if self.last_seq().await? > synced {
+            return Ok(());
+        }
+
+        let history = history(&self.commits().await?);
+        let result = self.pull(&mut logged, &history).await;
+        self.check_conflict(result)
+    }
+
+    /// Upload changes made since the last sync, if any.
+    ///
+    /// The remote store is only queried if there are.
+    pub async fn upload(&self) -> Result<()> {
+        let mut logged = self.logged.lock().await;
+
+        if self.is_diverged() {
+            return Err(Error::Conflict);
+        }
+        let (synced, synced_id) = self.sync_state().await?;
+        if self.last_seq().await? <= synced || self.read_only {
+            return Ok(());
+        }
+        let result = self.push(&mut logged, synced, synced_id).await;
+        self.check_conflict(result)
+    }
+
+    /// Upload the changes after sequence number `synced`, which are on top
+    /// of the commit `synced_id`, or of the commits of other devices since.
+    ///
+    /// The change log is replaced by a snapshot once it would be as large as
+    /// the database.
+    async fn push(&self, logged: &mut u64, mut synced: i64, mut synced_id: i32) -> Result<()> {
+        let commits = self.commits().await?;
+        let history = history(&commits);
+        if let Some(head) = history.last().filter(|commit| commit.info.id != synced_id) {
+            // The remote copy has moved on meanwhile, go on from there.
+            self.pull(logged, &history).await?;
+            synced = self.sync_state().await?.0;
+            synced_id = head.info.id;
+        }
+
+        // Change logs can only follow a remote snapshot or the first one.
+        let log = if self.incremental && !(history.is_empty() && synced_id != 0) {
+            let entries =
+                sqlx::query("SELECT seq, change FROM change_log WHERE seq>$1 ORDER BY seq")
+                    .bind(synced)
+                    .map(|row| (row.get::<i64, _>(0), row.get::<String, _>(1)))
+                    .fetch_all(&self.db)
+                    .await?;
+            let data = encode(&entries);
+            // The database without the entries, as a snapshot would hold it.
+            let size: i64 = sqlx::query_scalar(
+                "SELECT (page_count - freelist_count) * page_size \
+                 FROM pragma_page_count(), pragma_freelist_count(), pragma_page_size()",
+            )
+            .fetch_one(&self.db)
+            .await?;
+            let size = (size as u64).saturating_sub(data.len() as u64);
+            (*logged + data.len() as u64 <= size).then(|| {
+                let last = entries.last().map_or(synced, |(seq, _)| *seq);
+                (data, last)
+            })
+        } else {
+            None
+        };
+
+        let (id, seq) = if let Some((data, last)) = &log {
+            let name = Commit::name(synced_id, *last, false);
+            let id = self
+                .retry
+                .run_at_most_once("upload change log", || async {
+                    let mut stream = data.as_slice();
+                    self.store
+                        .put(LOG_TITLE, &name, &mut stream, data.len())
+                        .await
+                })
+                .await?;
+            log::info!("Upload {} to Telegram", name);
+            (id, *last)
+        } else {
+            self.upload_snapshot(synced_id).await?
+        };
+
+        // Another device uploading from the same base got in first.
+        let known = commits
+            .iter()
+            .map(|commit| commit.info.id)
+            .collect::<Vec<_>>();
+        let raced = self
+            .commits()
+            .await?
+            .iter()
+            .any(|commit| commit.info.id < id && !known.contains(&commit.info.id));
+        if raced {
+            // Its changes come first, ours are uploaded after them.
+            self.delete(id).await?;
+            let commits = self.commits().await?;
+            return self.pull(logged, &self::history(&commits)).await;
+        }
+
+        if let Some((data, _)) = &log {
+            *logged += data.len() as u64;
+        } else {
+            // Older commits are only dropped once the snapshot is in place.
+            for commit in self.commits().await? {
+                if commit.info.id < id {
+                    self.delete(commit.info.id).await?;
+                }
+            }
+            *logged = 0;
+        }
+
+        let mut tx = self.db.begin().await?;
+        Self::mark_synced(&mut tx, seq, id).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Remember a conflict, changes made from now on could never be uploaded.
+    fn check_conflict(&self, result: Result<()>) -> Result<()> {
+        if let Err(Error::Conflict) = result {
+            self.diverged.store(true, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Apply the commits of `history` following the database, leaving the
+    /// chunks of changed files to `take_stale`.
+    ///
+    /// Local changes not uploaded yet are moved after them, to be uploaded on
+    /// top. Rows changed on both sides are a conflict, as is a snapshot
+    /// replacing the commits local changes follow.
+    async fn pull(&self, logged: &mut u64, history: &[Commit]) -> Result<()> {
+        let (_, synced_id) = self.sync_state().await?;
+        let pending = match position(history, synced_id) {
+            Some(idx) => &history[idx..],
+            None => match history.first() {
+                Some(first) if first.snapshot => {
+                    self.load_snapshot(first).await?;
+                    &history[1..]
+                }
+                _ => return Err(Error::Conflict),
+            },
+        };
+        *logged = history
+            .iter()
+            .filter(|commit| !commit.snapshot)
+            .map(|commit| commit.info.size)
+            .sum();
+        if pending.is_empty() {
+            return Ok(());
+        }
+
+        let mut tx = self.db.begin().await?;
+        drop_triggers(&mut tx).await?;
+        let (mut applied,): (i64,) = sqlx::query_as("SELECT synced_seq FROM sync_state")
+            .fetch_one(&mut tx)
+            .await?;
+        let local: Vec<(String, String)> =
+            sqlx::query_as("SELECT key, change FROM change_log WHERE seq>$1 ORDER BY seq")
+                .bind(applied)
+                .fetch_all(&mut tx)
+                .await?;
+        sqlx::query("DELETE FROM change_log WHERE seq>$1")
+            .bind(applied)
+            .execute(&mut tx)
+            .await?;
+        let keys = local
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<HashSet<_>>();
+        let mut changed = BTreeSet::new();
+        for commit in pending {
+            let data = self
+                .retry
+                .run("download change log", || {
+                    fetch_all(self.store.as_ref(), commit.info.id)
+                })
+                .await?;
+
+            for (seq, change) in decode(&data)? {
+                if seq <= applied {
+                    continue;
+                }
+                let parsed = Change::parse(&change)?;
+                if keys.contains(parsed.key().as_str()) {
+                    return Err(Error::Conflict);
+                }
+                parsed.apply(&mut tx).await?;
+                changed.extend(parsed.content_ino());
+                // Keep the numbering in step with the device that wrote it.
+                sqlx::query("INSERT INTO change_log (seq, change) VALUES ($1, $2)")
+                    .bind(seq)
+                    .bind(&change)
+                    .execute(&mut tx)
+                    .await?;
+                applied = seq;
+            }
+            log::info!("Apply {} from Telegram", commit.info.name);
+        }
+        for (seq, (key, change)) in (applied + 1..).zip(&local) {
+            sqlx::query("INSERT INTO change_log (seq, key, change) VALUES ($1, $2, $3)")
+                .bind(seq)
+                .bind(key)
+                .bind(change)
+                .execute(&mut tx)
+                .await?;
+        }
+        // Leave no gap after the moved entries, it would look pending.
+        sqlx::query("UPDATE sqlite_sequence SET seq=$1 WHERE name='change_log'")
+            .bind(applied + local.len() as i64)
+            .execute(&mut tx)
+            .await?;
+        create_triggers(&mut tx).await?;
+        let head = history.last().map_or(synced_id, |commit| commit.info.id);
+        Self::mark_synced(&mut tx, applied, head).await?;
+        let mut stale = Vec::new();
+        for ino in changed {
+            let remote_ids: Vec<i32> =
+                sqlx::query_scalar("SELECT remote_id FROM chunk WHERE ino=$1")
+                    .bind(ino)
+                    .fetch_all(&mut tx)
+                    .await?;
+            stale.extend(remote_ids);
+        }
+        tx.commit().await?;
+        self.stale.lock().unwrap().extend(stale);
+
+        Ok(())
+    }
+
+    /// Replace the contents of the open database with a remote snapshot.
+    async fn load_snapshot(&self, commit: &Commit) -> Result<()> {
+        let path = self.db_path.with_extension("download");
+        self.retry
+            .run("download snapshot", || {
+                download(self.store.as_ref(), commit.info.id, &path)
+            })
+            .await?;
+
+        let mut conn = self.db.acquire().await?;
+        sqlx::query("ATTACH DATABASE $1 AS snapshot")
+            .bind(path.to_string_lossy())
+            .execute(&mut conn)
+            .await?;
+        let result = async {
+            let mut tx = conn.begin().await?;
+            drop_triggers(&mut tx).await?;
+            Self::check_pending(&mut tx).await?;
+            for (table, keys, columns) in LOGGED_TABLES {
+                let names = keys.iter().chain(*columns).copied().collect::<Vec<_>>();
+                let names = names.join(", ");
+                sqlx::query(&format!("DELETE FROM main.{table}"))
+                    .execute(&mut tx)
+                    .await?;
+                let sql = format!(
+                    "INSERT INTO main.{table} ({names}) SELECT {names} FROM snapshot.{table}"
+                );
+                sqlx::query(&sql).execute(&mut tx).await?;
+            }
+            // New inodes and change-log entries are numbered after its own.
+            sqlx::query("DELETE FROM main.sqlite_sequence")
+                .execute(&mut tx)
+                .await?;
+            sqlx::query("INSERT INTO main.sqlite_sequence SELECT * FROM snapshot.sqlite_sequence")
+                .execute(&mut tx)
+                .await?;
+            create_triggers(&mut tx).await?;
+            Self::mark_synced(&mut tx, commit.seq, commit.info.id).await?;
+            // Any file may have changed.
+            let stale: Vec<i32> = sqlx::query_scalar("SELECT remote_id FROM main.chunk")
+                .fetch_all(&mut tx)
+                .await?;
+            tx.commit().await?;
+            Ok::<_, Error>(stale)
+        }
+        .await;
+        sqlx::query("DETACH DATABASE snapshot")
+            .execute(&mut conn)
+            .await?;
+        let _ = tokio::fs::remove_file(&path).await;
+        self.stale.lock().unwrap().extend(result?);
+        log::info!("Load {} from Telegram", commit.info.name);
+
+        Ok(())
+    }
+
+    /// Upload a copy of the database following the commit `base`, returning
+    /// its id and the last sequence number it holds.
+    async fn upload_snapshot(&self, base: i32) -> Result<(i32, i64)> {
+        let snapshot = self.db_path.with_extension("snapshot");
+        let _ = tokio::fs::remove_file(&snapshot).await;
+        // A consistent copy, unlike the live file which may be mid-write.
+        sqlx::query("VACUUM INTO $1")
+            .bind(snapshot.to_string_lossy())
+            .execute(&self.db)
+            .await?;
+
+        let result = async {
+            let seq = local_state(&snapshot).await?.unwrap_or_default().last;
+            let name = Commit::name(base, seq, true);
+            let id = self
+                .retry
+                .run_at_most_once("upload snapshot", || async {
+                    let mut file = tokio::fs::File::open(&snapshot).await?;
+                    let size = file.metadata().await?.len() as usize;
+                    self.store.put(DB_TITLE, &name, &mut file, size).await
+                })
+                .await?;
+            log::info!("Upload {} to Telegram", name);
+            Ok((id, seq))
+        }
+        .await;
+        let _ = tokio::fs::remove_file(&snapshot).await;
+
+        result
+    }
+
+    /// All remote commits, see `remote_commits`.
+    async fn commits(&self) -> Result<Vec<Commit>> {
+        self.retry
+            .run("list metadata commits", || {
+                remote_commits(self.store.as_ref())
+            })
+            .await
+    }
+
+    async fn delete(&self, id: i32) -> Result<()> {
+        self.retry
+            .run("delete metadata commit", || self.store.delete(id))
+            .await
+    }
+
+    /// Fail with a conflict if an entry is pending within `tx`.
+    async fn check_pending(tx: &mut Transaction<'_, Sqlite>) -> Result<()> {
+        let (last, synced): (i64, i64) = sqlx::query_as(
+            "
+            SELECT IFNULL((SELECT seq FROM sqlite_sequence WHERE name='change_log'), 0),
+                synced_seq
+            FROM sync_state
+            ",
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        if last > synced {
+            return Err(Error::Conflict);
+        }
+
+        Ok(())
+    }
+
+    /// Last sequence number allocated, entries may have been dropped since.
+    async fn last_seq(&self) -> Result<i64> {
+        let seq = sqlx::query_scalar("SELECT seq FROM sqlite_sequence WHERE name='change_log'")
+            .fetch_optional(&self.db)
+            .await?;
+        Ok(seq.unwrap_or_default())
+    }
+
+    /// Last sequence number uploaded, and the id of the commit holding it.
+    async fn sync_state(&self) -> Result<(i64, i32)> {
+        let state = sqlx::query_as("SELECT synced_seq, synced_id FROM sync_state")
+            .fetch_one(&self.db)
+            .await?;
+        Ok(state)
+    }
+
+    /// Record changes up to `seq` as uploaded in the commit `id`, they are
+    /// not needed anymore.
+    async fn mark_synced(tx: &mut Transaction<'_, Sqlite>, seq: i64, id: i32) -> Result<()> {
+        sqlx::query("UPDATE sync_state SET synced_seq=$1, synced_id=$2")
+            .bind(seq)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("DELETE FROM change_log WHERE seq<=$1")
+            .bind(seq)
+            .execute(&mut *tx)
+            .await?;
+
+        Ok(())
+    }
+}
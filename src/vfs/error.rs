@@ -1,5 +1,6 @@
 use fuser::FileType;
 use grammers_client::types::iter_buffer::InvocationError;
+use std::time::Duration;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -18,6 +19,20 @@ pub enum Error {
     FileExists,
     #[error("File changed in remote side, please re-open it")]
     Invalidated,
+    #[error("Invalid argument")]
+    InvalidArgument,
+    #[error("Operation not permitted")]
+    NotPermitted,
+    #[error("No such attribute")]
+    NoAttribute,
+    #[error("Result too large")]
+    OutOfRange,
+    #[error("Bad file handle")]
+    BadFileHandle,
+    #[error("File of the handle was removed")]
+    Stale,
+    #[error("Read-only file system")]
+    ReadOnly,
 
     // sql error
     #[error("sql error: {0}")]
@@ -30,6 +45,12 @@ pub enum Error {
     DownloadFailed,
     #[error("Media invalid")]
     MediaInvalid,
+    #[error("Upload failed")]
+    UploadFailed,
+    #[error("Malformed metadata change log")]
+    MalformedLog,
+    #[error("Metadata changed on another device")]
+    Conflict,
 
     // IO error.
     #[error("IO error: {0}")]
@@ -50,6 +71,16 @@ impl Error {
             Self::DirectoryNotEmpty => libc::ENOTEMPTY,
             Self::FileExists => libc::EEXIST,
             Self::Invalidated => libc::EPERM,
+            Self::InvalidArgument => libc::EINVAL,
+            Self::NotPermitted => libc::EPERM,
+            #[cfg(target_os = "macos")]
+            Self::NoAttribute => libc::ENOATTR,
+            #[cfg(not(target_os = "macos"))]
+            Self::NoAttribute => libc::ENODATA,
+            Self::OutOfRange => libc::ERANGE,
+            Self::BadFileHandle => libc::EBADF,
+            Self::Stale => libc::ESTALE,
+            Self::ReadOnly => libc::EROFS,
 
             // sql error
             Self::Sql(_) => {
@@ -64,7 +95,10 @@ impl Error {
                 log::debug!("{:?}", self);
                 libc::EIO
             }
-            Self::DownloadFailed | Self::MediaInvalid => libc::EIO,
+            Self::DownloadFailed | Self::MediaInvalid | Self::UploadFailed | Self::MalformedLog => {
+                libc::EIO
+            }
+            Self::Conflict => libc::EIO,
 
             // Network errors.
             Self::Io(_) => {
@@ -80,4 +114,49 @@ impl Error {
             }
         }
     }
+
+    /// Whether the operation may succeed if tried again: network and IO
+    /// errors, flood waits and internal errors of Telegram, whose negative
+    /// codes are timeouts.
+    pub fn is_transient(&self) -> bool {
+        match self.invocation() {
+            Some(InvocationError::Rpc(err)) => {
+                err.name == "FLOOD_WAIT" || err.code >= 500 || err.code < 0
+            }
+            Some(InvocationError::Dropped | InvocationError::Read(_)) => true,
+            None => matches!(self, Self::Io(_)),
+        }
+    }
+
+    /// Whether the request may have been carried out all the same, the
+    /// connection being lost before the answer.
+    ///
+    /// Uploads of file parts come before the request using them, their
+    /// errors are wrapped in IO errors.
+    pub fn is_unanswered(&self) -> bool {
+        matches!(
+            self,
+            Self::Grammers(InvocationError::Dropped | InvocationError::Read(_))
+        )
+    }
+
+    /// Time Telegram asks us to wait before the next request.
+    pub fn flood_wait(&self) -> Option<Duration> {
+        match self.invocation() {
+            Some(InvocationError::Rpc(err)) if err.name == "FLOOD_WAIT" => {
+                Some(Duration::from_secs(err.value.unwrap_or_default() as u64))
+            }
+            _ => None,
+        }
+    }
+
+    /// The Telegram error behind this one, also when grammers wrapped it in
+    /// an IO error while uploading file parts.
+    fn invocation(&self) -> Option<&InvocationError> {
+        match self {
+            Self::Grammers(err) => Some(err),
+            Self::Io(err) => err.get_ref()?.downcast_ref(),
+            _ => None,
+        }
+    }
 }
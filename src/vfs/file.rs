@@ -1,27 +1,36 @@
+use crate::vfs::inode::unix_time;
+use crate::vfs::journal::{Journal, StoredFile};
+use crate::vfs::retry::RetryPolicy;
+use crate::vfs::store::{BlobInfo, RemoteStore};
 use crate::vfs::{Error, Result};
 
 use bytes::Bytes;
-use grammers_client::types::media::Uploaded;
-use grammers_client::types::{Chat, Media};
-use grammers_client::{Client, InputMessage};
 use lru::LruCache;
 use std::io::{self, SeekFrom};
-use std::num::NonZeroUsize;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex as SyncMutex};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Instant, SystemTime};
 use tokio::sync::{watch, MutexGuard};
 use tokio::{
-    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, DuplexStream},
     sync::Mutex,
 };
 
+/// Maximum number of open cache files, others are closed but kept on disk.
 const CACHE_SIZE: usize = 1024;
+/// Amount of cached data held in memory at once while uploading.
+const UPLOAD_PIECE_SIZE: usize = 512 * 1024;
 
 struct FileCacheState {
     file_size: u64,
+    /// Bumped on every modification.
+    revision: u64,
     available_size: watch::Receiver<u64>,
     file: tokio::fs::File,
+    path: PathBuf,
+    /// Remote version the content matches, if it is clean.
+    version: Option<i64>,
     status: FileCacheStatus,
 }
 
@@ -36,34 +45,85 @@ enum FileCacheStatus {
         lock_mtime: Instant,
         done_rx: watch::Receiver<bool>,
     },
+    /// Dirty without an upload in progress, since the last one failed even
+    /// after retrying or was interrupted by a restart.
+    UploadFailed,
     Invalidated,
 }
 
 pub struct FileCache {
     remote_id: i32,
+    journal: Arc<Journal>,
+    /// Mirror of `file_size`, readable without locking.
+    size: AtomicU64,
     state: Mutex<FileCacheState>,
 }
 
 impl FileCache {
     fn new(
         remote_id: i32,
-        file: tokio::fs::File,
+        (file, path): (std::fs::File, PathBuf),
+        journal: Arc<Journal>,
         file_size: u64,
+        version: Option<i64>,
         status: FileCacheStatus,
     ) -> (Arc<Self>, watch::Sender<u64>) {
         let (tx, rx) = watch::channel(0);
         let this = Arc::new(Self {
             remote_id,
+            journal,
+            size: AtomicU64::new(file_size),
             state: Mutex::new(FileCacheState {
                 file_size,
+                revision: 0,
                 available_size: rx,
-                file,
+                file: file.into(),
+                path,
+                version,
                 status,
             }),
         });
         (this, tx)
     }
 
+    fn set_size(&self, state: &mut FileCacheState, file_size: u64) {
+        state.file_size = file_size;
+        self.size.store(file_size, Ordering::Relaxed);
+    }
+
+    fn mark_dirty(&self, state: &mut FileCacheState) {
+        state.revision += 1;
+        // The stored name would claim the old version after a restart.
+        if state.version.take().is_some() {
+            match self.journal.rename_temporary(self.remote_id, &state.path) {
+                Ok(path) => state.path = path,
+                Err(err) => log::warn!("Failed to rename {:?} {}", state.path, err),
+            }
+        }
+        if let FileCacheStatus::Ready = state.status {
+            let (done_tx, done_rx) = watch::channel(false);
+            state.status = FileCacheStatus::Dirty {
+                lock_mtime: Instant::now(),
+                done_rx,
+            };
+            let _ = done_tx.send(true);
+        }
+    }
+
+    /// Keep the content across restarts as that of `version`.
+    fn persist(&self, state: &mut FileCacheState, version: i64) {
+        match self
+            .journal
+            .rename_stored(self.remote_id, version, &state.path)
+        {
+            Ok(path) => {
+                state.path = path;
+                state.version = Some(version);
+            }
+            Err(err) => log::warn!("Failed to rename {:?} {}", state.path, err),
+        }
+    }
+
     pub async fn read(this: &Arc<Self>, offset: u64, size: usize) -> Result<Bytes> {
         let mut guard = this.state.lock().await;
         let file_size = guard.file_size;
@@ -73,7 +133,9 @@ impl FileCache {
         let end = offset + size as u64;
 
         match guard.status {
-            FileCacheStatus::Ready | FileCacheStatus::Dirty { .. } => {}
+            FileCacheStatus::Ready
+            | FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::UploadFailed => {}
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } if end <= *guard.available_size.borrow() => {}
@@ -89,6 +151,7 @@ impl FileCache {
                     FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
                     FileCacheStatus::Ready
                     | FileCacheStatus::Dirty { .. }
+                    | FileCacheStatus::UploadFailed
                     | FileCacheStatus::Downloading { .. } => {}
                 }
             }
@@ -103,11 +166,13 @@ impl FileCache {
         Ok(buf.into())
     }
 
-    async fn write(self: &Arc<Self>, offset: u64, data: &[u8]) -> Result<(u64, u32)> {
+    pub async fn write(self: &Arc<Self>, offset: u64, data: &[u8]) -> Result<(u64, i64)> {
         let mut guard = self.state.lock().await;
 
         match guard.status {
-            FileCacheStatus::Ready | FileCacheStatus::Dirty { .. } => {}
+            FileCacheStatus::Ready
+            | FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::UploadFailed => {}
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } => {
@@ -123,22 +188,12 @@ impl FileCache {
             FileCacheStatus::Invalidated => return Err(Error::Invalidated),
             FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
             FileCacheStatus::Downloading { .. } => unreachable!(),
-            FileCacheStatus::Dirty { .. } => {}
-            FileCacheStatus::Ready => {
-                let (done_tx, done_rx) = watch::channel(false);
-                let init_lock_mtime = Instant::now();
-                guard.status = FileCacheStatus::Dirty {
-                    lock_mtime: init_lock_mtime,
-                    done_rx,
-                };
-                let _ = done_tx.send(true);
-            }
+            FileCacheStatus::Dirty { .. }
+            | FileCacheStatus::Ready
+            | FileCacheStatus::UploadFailed => self.mark_dirty(&mut guard),
         }
 
-        let mtime = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+        let mtime = unix_time(SystemTime::now());
 
         guard.file.seek(SeekFrom::Start(offset)).await?;
         guard.file.write_all(data).await?;
@@ -150,72 +205,89 @@ impl FileCache {
             guard.file_size,
             new_size,
         );
-        guard.file_size = new_size;
+        self.set_size(&mut guard, new_size);
 
         Ok((guard.file_size, mtime))
     }
 
+    /// Mark a finished download as ready, or upload it if it was truncated
+    /// meanwhile.
+    async fn complete_download(
+        self: &Arc<Self>,
+        mut guard: MutexGuard<'_, FileCacheState>,
+        download_size: u64,
+        info: &BlobInfo,
+        store: &Arc<dyn RemoteStore>,
+        retry: &RetryPolicy,
+    ) {
+        log::debug!(
+            "Cache {:?} is fully available (downloaded {} bytes, total {} bytes)",
+            self.remote_id,
+            download_size,
+            guard.file_size,
+        );
+
+        match guard.status {
+            FileCacheStatus::Downloading { truncate: Some(_) } => {
+                log::debug!(
+                    "Pending upload for truncated file {:?}, size: {}",
+                    self.remote_id,
+                    guard.file_size,
+                );
+
+                // Recorded like in `flush`, so the upload is resumed after a crash.
+                if let Err(err) = self
+                    .journal
+                    .add(self.remote_id, &info.name, &guard.path)
+                    .await
+                {
+                    log::error!("Failed to queue upload of {:?} {}", self.remote_id, err);
+                }
+                self.upload(&mut guard, &info.name, store, retry);
+            }
+            FileCacheStatus::Downloading { truncate: None } => {
+                guard.status = FileCacheStatus::Ready;
+                self.persist(&mut guard, info.version);
+            }
+            _ => unreachable!(),
+        }
+    }
+
     async fn download(
         this: Arc<FileCache>,
         tx: watch::Sender<u64>,
-        media: Media,
-        client: Client,
-        chat: Chat,
+        info: BlobInfo,
+        store: Arc<dyn RemoteStore>,
+        retry: RetryPolicy,
         file_size: u64,
     ) {
         log::debug!("Start downloading ({} bytes)", file_size);
 
         let mut pos = 0u64;
 
-        let complete = |mut guard: MutexGuard<'_, FileCacheState>, download_size: u64| {
-            log::debug!(
-                "Cache {:?} is fully available (downloaded {} bytes, total {} bytes)",
-                this.remote_id,
-                download_size,
-                guard.file_size,
-            );
-
-            match guard.status {
-                FileCacheStatus::Downloading { truncate: Some(_) } => {
-                    log::debug!(
-                        "Pending upload for truncated file {:?}, size: {}",
-                        this.remote_id,
-                        guard.file_size,
-                    );
-
-                    let document = if let Media::Document(document) = media.clone() {
-                        document
-                    } else {
-                        unreachable!();
-                    };
-
-                    this.upload(&mut guard, document.name(), &client, &chat);
-                }
-                FileCacheStatus::Downloading { truncate: None } => {
-                    guard.status = FileCacheStatus::Ready;
-                }
-                _ => unreachable!(),
+        let mut reader = match store.fetch(this.remote_id).await {
+            Ok(reader) => reader,
+            Err(err) => {
+                log::error!("Failed to fetch {:?} {}", this.remote_id, err);
+                this.state.lock().await.status = FileCacheStatus::DownloadFailed;
+                return;
             }
         };
-
-        let mut iter = client.iter_download(&media);
         loop {
-            let ret = iter.next().await;
+            let ret = reader.next().await;
 
             let mut guard = this.state.lock().await;
 
-            match ret {
+            let mut chunk = match ret {
                 Err(_) => {
                     guard.status = FileCacheStatus::DownloadFailed;
                     return;
                 }
-                Ok(chunk) if chunk == None => {
+                Ok(None) => {
                     break;
                 }
-                _ => (),
-            }
-
-            let mut chunk = ret.unwrap().unwrap();
+                Ok(Some(chunk)) => chunk,
+            };
 
             let download_size = match guard.status {
                 FileCacheStatus::Downloading {
@@ -261,7 +333,8 @@ impl FileCache {
                 // Space after data written is already zero as expected.
                 tx.send(guard.file_size).unwrap();
 
-                complete(guard, download_size);
+                this.complete_download(guard, download_size, &info, &store, &retry)
+                    .await;
                 log::debug!("Download finished ({} bytes)", file_size);
 
                 return;
@@ -270,9 +343,7 @@ impl FileCache {
 
         let mut guard = this.state.lock().await;
         let download_size = match guard.status {
-            FileCacheStatus::Downloading { truncate } => {
-                truncate.map(|sz| sz).unwrap_or(guard.file_size)
-            }
+            FileCacheStatus::Downloading { truncate } => truncate.unwrap_or(guard.file_size),
             FileCacheStatus::Invalidated => return,
             _ => unreachable!(),
         };
@@ -287,7 +358,8 @@ impl FileCache {
             guard.status = FileCacheStatus::DownloadFailed;
         } else {
             // File is set to a larger length than remote side.
-            complete(guard, download_size);
+            this.complete_download(guard, download_size, &info, &store, &retry)
+                .await;
             log::debug!("Download finished ({} bytes)", file_size);
         }
     }
@@ -296,8 +368,8 @@ impl FileCache {
         self: &Arc<Self>,
         guard: &mut MutexGuard<'_, FileCacheState>,
         name: &str,
-        client: &Client,
-        chat: &Chat,
+        store: &Arc<dyn RemoteStore>,
+        retry: &RetryPolicy,
     ) {
         let (done_tx, done_rx) = watch::channel(false);
         let init_lock_mtime = Instant::now();
@@ -307,198 +379,390 @@ impl FileCache {
         };
 
         let this = self.clone();
-        let file_name = String::from(name);
-        let tmp_client = client.clone();
-        let tmp_chat = chat.clone();
+        let name = String::from(name);
+        let store = store.clone();
+        let retry = retry.clone();
         tokio::spawn(async move {
             let is_up_to_date = |status: &FileCacheStatus| matches!(status, FileCacheStatus::Dirty { lock_mtime, .. } if *lock_mtime == init_lock_mtime);
 
-            let name = file_name;
-            let chat = tmp_chat;
-            let client = tmp_client;
-
             // Check not changed since last lock.
-            let file_size = {
+            let (file_size, revision) = {
                 let guard = this.state.lock().await;
                 if !is_up_to_date(&guard.status) {
                     return;
                 }
-                guard.file_size
+                (guard.file_size, guard.revision)
             };
 
-            let uploaded: Uploaded;
-            {
-                let mut guard = this.state.lock().await;
+            let what = format!("upload file of {}", this.remote_id);
+            let ret = retry
+                .run(&what, || {
+                    this.upload_once(store.as_ref(), &name, file_size, revision)
+                })
+                .await;
+            // Editing gives the document a new version.
+            let version = match ret {
+                Ok(true) => store
+                    .stat(this.remote_id)
+                    .await
+                    .ok()
+                    .flatten()
+                    .map(|info| info.version),
+                _ => None,
+            };
 
-                if !is_up_to_date(&guard.status) {
-                    log::debug!("Upload of {:?} outdates", this.remote_id);
+            let mut guard = this.state.lock().await;
+            match ret {
+                Ok(true) => {}
+                Ok(false) => {
+                    // Modified while streaming, what was sent may be torn.
+                    if is_up_to_date(&guard.status) {
+                        log::debug!("Cache {:?} modified during upload, restart", this.remote_id);
+                        this.upload(&mut guard, &name, &store, &retry);
+                    }
+                    return;
+                }
+                Err(err) => {
+                    log::error!(
+                        "Failed to upload file of {} ({} bytes) {}",
+                        this.remote_id,
+                        file_size,
+                        err,
+                    );
+                    // Keep the data, the next flush tries again.
+                    if is_up_to_date(&guard.status) {
+                        guard.status = FileCacheStatus::UploadFailed;
+                    }
                     return;
                 }
+            }
 
-                assert_eq!(file_size, guard.file_size, "Truncation restarts uploading");
-                if file_size == 0 {
-                    let buf = vec![0];
-                    let mut stream = std::io::Cursor::new(buf);
-
-                    let uploaded_file = match client
-                        .clone()
-                        .upload_stream(&mut stream, 1, name.clone())
-                        .await
-                    {
-                        Ok(f) => f,
-                        Err(err) => {
-                            log::error!(
-                                "Failed to upload file of {} ({} bytes) {}",
-                                this.remote_id,
-                                0,
-                                err,
-                            );
-                            // TODO: retry
-                            return;
-                        }
-                    };
-                    uploaded = uploaded_file;
-                } else {
-                    let mut buf = vec![0u8; file_size as usize];
-                    if let Err(err) = guard.file.seek(SeekFrom::Start(0)).await {
-                        log::error!("Failed to seek file {:?} {}", guard.file, err);
+            log::info!("Upload file of {} successful", this.remote_id);
+
+            match guard.status {
+                FileCacheStatus::Downloading { .. } => unreachable!(),
+                FileCacheStatus::Dirty { lock_mtime, .. } if lock_mtime == init_lock_mtime => {
+                    if guard.revision != revision {
+                        log::debug!("Cache {:?} modified after upload, restart", this.remote_id);
+                        this.upload(&mut guard, &name, &store, &retry);
                         return;
                     }
-                    if let Err(err) = guard.file.read_exact(&mut buf).await {
-                        log::error!("Failed to read file {:?} {}", guard.file, err);
-                        return;
+                    guard.status = FileCacheStatus::Ready;
+                    if let Err(err) = this.journal.remove(this.remote_id).await {
+                        log::error!("Failed to dequeue upload of {} {}", this.remote_id, err);
                     }
-
-                    drop(guard);
-
-                    let mut stream = std::io::Cursor::new(buf);
-                    let uploaded_file = match client
-                        .upload_stream(&mut stream, file_size as usize, name.clone())
-                        .await
-                    {
-                        Ok(f) => f,
-                        Err(err) => {
-                            log::error!(
-                                "Failed to upload file of {} ({} bytes) {}",
-                                this.remote_id,
-                                file_size,
-                                err,
-                            );
-                            // TODO: retry
-                            return;
-                        }
-                    };
-                    uploaded = uploaded_file;
+                    if let Some(version) = version {
+                        this.persist(&mut guard, version);
+                    }
+                }
+                FileCacheStatus::Invalidated => {
+                    log::warn!(
+                        "Cache invalidated during the upload of {:?}, maybe both changed? Suppress update event",
+                        this.remote_id,
+                    );
+                    return;
+                }
+                // Race another upload.
+                _ => {
+                    log::debug!("Racing upload? Suppress update event");
+                    return;
                 }
             }
+            drop(guard);
 
-            if let Err(err) = client
-                .edit_message(
-                    chat,
-                    this.remote_id,
-                    InputMessage::text(name).file(uploaded),
+            let _ = done_tx.send(true);
+        });
+    }
+
+    /// Upload the cache as of `revision`.
+    ///
+    /// Returns `false` if the cache is modified in between.
+    async fn upload_once(
+        &self,
+        store: &dyn RemoteStore,
+        name: &str,
+        file_size: u64,
+        revision: u64,
+    ) -> Result<bool> {
+        // Empty files are marked by an empty caption.
+        let caption = if file_size == 0 { "" } else { name };
+        let (reader, writer) = tokio::io::duplex(UPLOAD_PIECE_SIZE);
+        let upload = async {
+            // Drop the reader once done, so copying stops if the upload fails.
+            let mut reader = reader;
+            store
+                .replace(
+                    self.remote_id,
+                    caption,
+                    name,
+                    &mut reader,
+                    file_size as usize,
                 )
                 .await
-            {
-                log::error!("Failed to edit message of {} {}", this.remote_id, err,);
-                return;
-            } else {
-                log::info!("Upload file of {} successful", this.remote_id);
+        };
+        let (copied, uploaded) = tokio::join!(self.copy_to(writer, file_size, revision), upload);
+
+        match (copied, uploaded) {
+            (Ok(false), _) => Ok(false),
+            // The pipe is closed if the upload fails first.
+            (_, Err(err)) => Err(err),
+            (Err(err), Ok(())) => Err(err.into()),
+            (Ok(true), Ok(())) => Ok(true),
+        }
+    }
 
-                {
-                    let mut guard = this.state.lock().await;
-                    match guard.status {
-                        FileCacheStatus::Downloading { .. } => unreachable!(),
-                        FileCacheStatus::Dirty { lock_mtime, .. }
-                            if lock_mtime == init_lock_mtime =>
-                        {
-                            guard.status = FileCacheStatus::Ready;
-                        }
-                        FileCacheStatus::Invalidated => {
-                            log::warn!(
-                                "Cache invalidated during the upload of {:?}, maybe both changed? Suppress update event",
-                                this.remote_id,
-                            );
-                            return;
-                        }
-                        // Race another upload.
-                        _ => {
-                            log::debug!("Racing upload? Suppress update event");
-                            return;
-                        }
-                    }
+    /// Stream the first `file_size` bytes of the cache into `writer`, one
+    /// piece at a time.
+    ///
+    /// Returns `false` if the cache is modified in between.
+    async fn copy_to(
+        &self,
+        mut writer: DuplexStream,
+        file_size: u64,
+        revision: u64,
+    ) -> io::Result<bool> {
+        let mut buf = vec![0u8; UPLOAD_PIECE_SIZE];
+        let mut pos = 0u64;
+        while pos < file_size {
+            let len = buf.len().min((file_size - pos) as usize);
+            {
+                let mut guard = self.state.lock().await;
+                if guard.revision != revision {
+                    return Ok(false);
                 }
-
-                let _ = done_tx.send(true);
+                guard.file.seek(SeekFrom::Start(pos)).await?;
+                guard.file.read_exact(&mut buf[..len]).await?;
             }
-        });
+            writer.write_all(&buf[..len]).await?;
+            pos += len as u64;
+        }
+
+        Ok(true)
     }
 }
 
 pub struct DiskCache {
-    dir: PathBuf,
     files: SyncMutex<LruCache<i32, Arc<FileCache>>>,
-    client: Client,
-    chat: Chat,
+    /// Clean files kept on disk without being open.
+    stored: SyncMutex<LruCache<i32, StoredFile>>,
+    /// Budget of cached bytes.
+    capacity: u64,
+    store: Arc<dyn RemoteStore>,
+    retry: RetryPolicy,
+    journal: Arc<Journal>,
 }
 
 impl DiskCache {
-    pub fn new(client: Client, chat: Chat) -> Self {
+    pub fn new(
+        store: Arc<dyn RemoteStore>,
+        retry: RetryPolicy,
+        journal: Journal,
+        capacity: u64,
+    ) -> Self {
         Self {
-            dir: PathBuf::new(),
-            files: SyncMutex::new(LruCache::new(NonZeroUsize::new(CACHE_SIZE).unwrap())),
-            client,
-            chat,
+            files: SyncMutex::new(LruCache::unbounded()),
+            stored: SyncMutex::new(LruCache::unbounded()),
+            capacity,
+            store,
+            retry,
+            journal: Arc::new(journal),
         }
     }
 
+    /// Pick up the files left by the last run, uploading again dirty ones.
+    pub async fn resume(&self) -> Result<()> {
+        let (pending, stored) = self.journal.recover().await?;
+
+        {
+            let mut files = self.stored.lock().unwrap();
+            for file in stored {
+                files.put(file.remote_id, file);
+            }
+        }
+
+        for upload in pending {
+            log::info!(
+                "Resume upload of {} from {:?}",
+                upload.remote_id,
+                upload.path
+            );
+
+            let file = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&upload.path)?;
+            let file_size = file.metadata()?.len();
+            let (file, _) = FileCache::new(
+                upload.remote_id,
+                (file, upload.path),
+                self.journal.clone(),
+                file_size,
+                None,
+                FileCacheStatus::UploadFailed,
+            );
+            self.files.lock().unwrap().put(upload.remote_id, file);
+
+            self.flush(upload.remote_id, &upload.name, false).await?;
+        }
+        self.evict();
+
+        Ok(())
+    }
+
     pub fn get(&self, remote_id: &i32) -> Option<Arc<FileCache>> {
         self.files.lock().unwrap().get_mut(remote_id).cloned()
     }
 
-    pub fn remove(&self, remote_id: &i32) {
-        self.files.lock().unwrap().pop(remote_id);
+    /// Drop the cache of `remote_id`, including its file.
+    pub async fn remove(&self, remote_id: &i32) {
+        let file = self.files.lock().unwrap().pop(remote_id);
+        if let Some(file) = file {
+            let path = file.state.lock().await.path.clone();
+            remove_file(&path);
+        }
+        let stored = self.stored.lock().unwrap().pop(remote_id);
+        if let Some(stored) = stored {
+            remove_file(&stored.path);
+        }
     }
 
-    pub async fn open(&self, remote_id: i32) -> Result<u64> {
-        if let Some(_) = self.get(&remote_id) {
+    pub async fn open(&self, remote_id: i32) -> Result<Arc<FileCache>> {
+        if let Some(file) = self.get(&remote_id) {
             log::debug!("File already cached: {}", remote_id);
-            return Ok(0);
+            return Ok(file);
         }
 
-        self.alloc(remote_id, None).await?;
+        let file = self.alloc(remote_id, None).await?;
+        self.evict();
 
-        Ok(0)
+        Ok(file)
     }
 
-    pub async fn open_create_empty(&self, name: &str) -> Result<(u64, i32)> {
+    pub async fn open_create_empty(&self, name: &str) -> Result<(Arc<FileCache>, i32)> {
         let remote_id = self.upload_empty_file(name, None).await?;
+        let file = self.open(remote_id).await?;
 
-        Ok((0, remote_id))
+        Ok((file, remote_id))
     }
 
     pub async fn delete(&self, remote_id: i32) -> Result<()> {
-        self.remove(&remote_id);
+        self.remove(&remote_id).await;
+        self.journal.remove(remote_id).await?;
 
-        if let Err(_) = self
-            .client
-            .delete_messages(&self.chat, &vec![remote_id])
-            .await
-        {}
+        let _ = self.store.delete(remote_id).await;
 
         Ok(())
     }
 
+    /// Drop the clean cache of `remote_id`, whose content was changed by
+    /// another device.
+    ///
+    /// Dirty files are kept, their upload replaces the remote content anyway.
+    pub async fn invalidate(&self, remote_id: i32) {
+        let file = match self.get(&remote_id) {
+            Some(file) => file,
+            None => return,
+        };
+        let mut guard = file.state.lock().await;
+        match guard.status {
+            FileCacheStatus::Ready | FileCacheStatus::DownloadFailed => {}
+            _ => return,
+        }
+
+        log::debug!("Invalidate cached file {:?}", guard.path);
+        guard.status = FileCacheStatus::Invalidated;
+        remove_file(&guard.path);
+        self.files.lock().unwrap().pop(&remote_id);
+    }
+
+    /// Shrink the cache below its budget, least recently used files first.
+    ///
+    /// Dirty files and files being read or written are never evicted. A file
+    /// that is open but idle may be, it is downloaded again when next read.
+    pub fn evict(&self) {
+        let mut files = self.files.lock().unwrap();
+        let mut stored = self.stored.lock().unwrap();
+
+        let mut used = files
+            .iter()
+            .map(|(_, file)| file.size.load(Ordering::Relaxed))
+            .chain(stored.iter().map(|(_, file)| file.size))
+            .sum::<u64>();
+
+        // Closed files were not used since the last run, or longer.
+        while used > self.capacity {
+            match stored.pop_lru() {
+                Some((_, file)) => {
+                    log::debug!("Evict cached file {:?}", file.path);
+                    remove_file(&file.path);
+                    used -= file.size;
+                }
+                None => break,
+            }
+        }
+        if used <= self.capacity && files.len() <= CACHE_SIZE {
+            return;
+        }
+
+        let lru: Vec<i32> = files
+            .iter()
+            .rev()
+            .map(|(&remote_id, _)| remote_id)
+            .collect();
+        for remote_id in lru {
+            let over_budget = used > self.capacity;
+            if !over_budget && files.len() <= CACHE_SIZE {
+                break;
+            }
+
+            let file = files.peek(&remote_id).unwrap();
+            if Arc::strong_count(file) > 1 {
+                continue;
+            }
+            let (path, version) = match file.state.try_lock() {
+                Ok(guard) => match guard.status {
+                    FileCacheStatus::Ready
+                    | FileCacheStatus::DownloadFailed
+                    | FileCacheStatus::Invalidated => (guard.path.clone(), guard.version),
+                    _ => continue,
+                },
+                Err(_) => continue,
+            };
+
+            let size = file.size.load(Ordering::Relaxed);
+            files.pop(&remote_id);
+            match version {
+                // Too many open files, close it but keep the content.
+                Some(version) if !over_budget => {
+                    stored.put(
+                        remote_id,
+                        StoredFile {
+                            remote_id,
+                            version,
+                            path,
+                            size,
+                        },
+                    );
+                }
+                _ => {
+                    log::debug!("Evict cached file {:?}", path);
+                    remove_file(&path);
+                    used -= size;
+                }
+            }
+        }
+    }
+
     pub async fn truncate_file(&self, remote_id: i32, new_size: u64, name: &str) -> Result<()> {
         if let Some(file) = self.get(&remote_id) {
             let mut guard = file.state.lock().await;
             match guard.status {
                 FileCacheStatus::Downloading { truncate } => {
-                    let download_size = truncate.map(|sz| sz).unwrap_or(guard.file_size);
+                    let download_size = truncate.unwrap_or(guard.file_size);
                     guard.status = FileCacheStatus::Downloading {
                         truncate: Some(download_size.min(new_size)),
                     };
-                    guard.file_size = new_size;
+                    file.set_size(&mut guard, new_size);
                     guard.file.set_len(new_size).await.unwrap();
                     log::debug!(
                         "Pending another truncate for still downloading file {}",
@@ -507,17 +771,18 @@ impl DiskCache {
 
                     return Ok(());
                 }
-                FileCacheStatus::Ready | FileCacheStatus::Dirty { .. } => {
+                FileCacheStatus::Ready
+                | FileCacheStatus::Dirty { .. }
+                | FileCacheStatus::UploadFailed => {
                     log::debug!(
                         "Truncated cached file {}: {} -> {}",
                         remote_id,
                         guard.file_size,
                         new_size,
                     );
-                    guard.file_size = new_size;
+                    file.set_size(&mut guard, new_size);
                     guard.file.set_len(new_size).await.unwrap();
-
-                    //file.upload(&mut guard, name, &self.client, &self.chat);
+                    file.mark_dirty(&mut guard);
 
                     return Ok(());
                 }
@@ -533,15 +798,6 @@ impl DiskCache {
         Ok(())
     }
 
-    pub async fn write_file(&self, remote_id: i32, offset: u64, data: &[u8]) -> Result<(u64, u32)> {
-        if let Some(file) = self.get(&remote_id) {
-            let (new_size, mtime) = file.write(offset, data).await?;
-            Ok((new_size, mtime))
-        } else {
-            Err(Error::NotFound)
-        }
-    }
-
     pub async fn flush(&self, remote_id: i32, name: &str, block: bool) -> Result<()> {
         if let Some(file) = self.get(&remote_id) {
             let mut guard = file.state.lock().await;
@@ -557,10 +813,21 @@ impl DiskCache {
                     while rx.changed().await.is_ok() {}
                     guard = file.state.lock().await;
                 }
-                FileCacheStatus::Dirty { .. } => {}
+                FileCacheStatus::Dirty { .. } | FileCacheStatus::UploadFailed => {}
             }
 
-            file.upload(&mut guard, name, &self.client, &self.chat);
+            match &guard.status {
+                FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
+                FileCacheStatus::Ready | FileCacheStatus::Invalidated => return Ok(()),
+                // Already uploading, like a download truncated meanwhile once
+                // finished. Changes made since are uploaded after it.
+                FileCacheStatus::Dirty { done_rx, .. } if !*done_rx.borrow() => {}
+                _ => {
+                    // Recorded before returning, so the upload is resumed after a crash.
+                    self.journal.add(remote_id, name, &guard.path).await?;
+                    file.upload(&mut guard, name, &self.store, &self.retry);
+                }
+            }
 
             if block {
                 loop {
@@ -568,6 +835,7 @@ impl DiskCache {
                         FileCacheStatus::Downloading { .. } => unreachable!(),
                         FileCacheStatus::DownloadFailed => return Err(Error::DownloadFailed),
                         FileCacheStatus::Invalidated | FileCacheStatus::Ready => return Ok(()),
+                        FileCacheStatus::UploadFailed => return Err(Error::UploadFailed),
                         FileCacheStatus::Dirty { done_rx, .. } => done_rx.clone(),
                     };
                     drop(guard);
@@ -588,88 +856,109 @@ impl DiskCache {
         }
     }
 
-    async fn alloc(&self, remote_id: i32, truncate: Option<u64>) -> Result<()> {
-        let msgs = self
-            .client
-            .get_messages_by_id(&self.chat, &vec![remote_id])
-            .await?;
-
-        if let Some(msg) = msgs.into_iter().nth(0) {
-            if let Some(raw_msg) = msg {
-                if raw_msg.text().is_empty() {
-                    self.insert_empty(raw_msg.id()).await?;
-                } else if let Some(media) = raw_msg.media() {
-                    if let Media::Document(_) = &media {
-                        self.try_alloc_and_fetch(remote_id, truncate, &media)?;
-                    } else {
-                        return Err(Error::MediaInvalid);
+    async fn alloc(&self, remote_id: i32, truncate: Option<u64>) -> Result<Arc<FileCache>> {
+        let stored = self.stored.lock().unwrap().pop(&remote_id);
+        match self.store.stat(remote_id).await? {
+            Some(info) if info.caption.is_empty() => self.insert_empty(info.id).await,
+            Some(info) => {
+                if let Some(stored) = stored {
+                    if stored.version == info.version && truncate.is_none() {
+                        return Ok(self.reuse(stored)?);
                     }
-                } else {
-                    return Err(Error::MediaInvalid);
+                    remove_file(&stored.path);
                 }
-                Ok(())
-            } else {
-                Err(Error::NotFound)
+                Ok(self.try_alloc_and_fetch(remote_id, truncate, info)?)
             }
-        } else {
-            Err(Error::NotFound)
+            None => Err(Error::NotFound),
+        }
+    }
+
+    /// Open a file kept from earlier instead of downloading it again.
+    fn reuse(&self, stored: StoredFile) -> io::Result<Arc<FileCache>> {
+        log::debug!("Reuse cached file {:?}", stored.path);
+
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&stored.path)?;
+        let (file, _) = FileCache::new(
+            stored.remote_id,
+            (file, stored.path),
+            self.journal.clone(),
+            stored.size,
+            Some(stored.version),
+            FileCacheStatus::Ready,
+        );
+
+        let mut files = self.files.lock().unwrap();
+        if let Some(file) = files.get(&stored.remote_id) {
+            return Ok(file.clone());
         }
+        files.put(stored.remote_id, file.clone());
+
+        Ok(file)
     }
 
     async fn insert_empty(&self, remote_id: i32) -> Result<Arc<FileCache>> {
+        let stored = self.stored.lock().unwrap().pop(&remote_id);
+        if let Some(stored) = stored {
+            remove_file(&stored.path);
+        }
+
         let (file, old) = {
             let mut files = self.files.lock().unwrap();
-            let tmp_file = tempfile::tempfile_in(&self.dir)?;
-            let (file, _) = FileCache::new(remote_id, tmp_file.into(), 0, FileCacheStatus::Ready);
+            let (file, _) = FileCache::new(
+                remote_id,
+                self.journal.create_file(remote_id)?,
+                self.journal.clone(),
+                0,
+                None,
+                FileCacheStatus::Ready,
+            );
             let old = files.put(remote_id, file.clone());
             (file, old)
         };
         if let Some(old) = old {
-            old.state.lock().await.status = FileCacheStatus::Invalidated;
+            let mut guard = old.state.lock().await;
+            guard.status = FileCacheStatus::Invalidated;
+            remove_file(&guard.path);
+            drop(guard);
+            self.journal.remove(remote_id).await?;
         }
         Ok(file)
     }
 
     async fn upload_empty_file(&self, name: &str, remote_id: Option<i32>) -> Result<i32> {
-        let buf = vec![0];
-        let mut stream = std::io::Cursor::new(buf);
-
-        let uploaded_file = self
-            .client
-            .upload_stream(&mut stream, 1, String::from(name))
-            .await?;
-
-        if let Some(id) = remote_id {
-            self.client
-                .edit_message(&self.chat, id, InputMessage::text("").file(uploaded_file))
+        let id = if let Some(id) = remote_id {
+            self.retry
+                .run("upload empty file", || async {
+                    let mut stream = tokio::io::empty();
+                    self.store.replace(id, "", name, &mut stream, 0).await
+                })
                 .await?;
-
-            self.insert_empty(id).await?;
-
-            Ok(id)
+            id
         } else {
-            let msg = self
-                .client
-                .send_message(&self.chat, InputMessage::text("").file(uploaded_file))
-                .await?;
+            // A message sent twice would be left over.
+            self.retry
+                .run_at_most_once("upload empty file", || async {
+                    let mut stream = tokio::io::empty();
+                    self.store.put("", name, &mut stream, 0).await
+                })
+                .await?
+        };
 
-            self.insert_empty(msg.id()).await?;
+        self.insert_empty(id).await?;
 
-            Ok(msg.id())
-        }
+        Ok(id)
     }
 
     fn try_alloc_and_fetch(
         &self,
         remote_id: i32,
         truncate: Option<u64>,
-        media: &Media,
-    ) -> io::Result<Option<Arc<FileCache>>> {
-        let media_size = if let Media::Document(document) = media {
-            document.size() as u64
-        } else {
-            unreachable!();
-        };
+        info: BlobInfo,
+    ) -> io::Result<Arc<FileCache>> {
+        let media_size = info.size;
         let (file_size, download_truncate) = match truncate {
             None => (media_size, None),
             Some(new_size) => (new_size, Some(media_size.min(new_size))),
@@ -677,16 +966,18 @@ impl DiskCache {
 
         let mut files = self.files.lock().unwrap();
         if let Some(state) = files.get_mut(&remote_id) {
-            return Ok(Some(state.clone()));
+            return Ok(state.clone());
         }
 
-        let tmp_file = tempfile::tempfile_in(&self.dir)?;
+        let (tmp_file, path) = self.journal.create_file(remote_id)?;
         tmp_file.set_len(file_size)?;
 
         let (file, tx) = FileCache::new(
             remote_id,
-            tmp_file.into(),
+            (tmp_file, path),
+            self.journal.clone(),
             file_size,
+            None,
             FileCacheStatus::Downloading {
                 truncate: download_truncate,
             },
@@ -696,12 +987,18 @@ impl DiskCache {
         tokio::spawn(FileCache::download(
             file.clone(),
             tx,
-            media.clone(),
-            self.client.clone(),
-            self.chat.clone(),
+            info,
+            self.store.clone(),
+            self.retry.clone(),
             file_size,
         ));
 
-        Ok(Some(file))
+        Ok(file)
+    }
+}
+
+fn remove_file(path: &Path) {
+    if let Err(err) = std::fs::remove_file(path) {
+        log::debug!("Failed to remove {:?} {}", path, err);
     }
 }
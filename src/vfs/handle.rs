@@ -0,0 +1,135 @@
+use crate::vfs::{Error, Result};
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// State of one open file or directory.
+#[derive(Debug, Clone, Copy)]
+pub struct FileHandle {
+    pub ino: u64,
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    /// Written through since it was opened.
+    pub dirty: bool,
+}
+
+impl FileHandle {
+    /// A handle with the access mode and `O_APPEND` of `flags`.
+    pub fn new(ino: u64, flags: i32) -> Self {
+        let mode = flags & libc::O_ACCMODE;
+        Self {
+            ino,
+            read: mode != libc::O_WRONLY,
+            write: mode != libc::O_RDONLY,
+            append: flags & libc::O_APPEND != 0,
+            dirty: false,
+        }
+    }
+}
+
+/// References the kernel holds on inodes: open handles by their number,
+/// which starts at 1 and is never reused, and lookup counts.
+pub struct HandleTable {
+    handles: Mutex<HashMap<u64, FileHandle>>,
+    next_fh: AtomicU64,
+    lookups: Mutex<HashMap<u64, u64>>,
+}
+
+impl HandleTable {
+    pub fn new() -> Self {
+        Self {
+            handles: Mutex::new(HashMap::new()),
+            next_fh: AtomicU64::new(1),
+            lookups: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Count an entry of `ino` replied to the kernel.
+    pub fn lookup(&self, ino: u64) {
+        *self.lookups.lock().unwrap().entry(ino).or_default() += 1;
+    }
+
+    /// Drop `count` lookups of `ino`, returning whether none is left.
+    pub fn forget(&self, ino: u64, count: u64) -> bool {
+        let mut lookups = self.lookups.lock().unwrap();
+        match lookups.get_mut(&ino) {
+            Some(lookup) if *lookup > count => {
+                *lookup -= count;
+                false
+            }
+            _ => {
+                lookups.remove(&ino);
+                true
+            }
+        }
+    }
+
+    /// Whether the kernel still knows `ino` by a lookup or an open handle.
+    pub fn is_referenced(&self, ino: u64) -> bool {
+        self.lookups.lock().unwrap().contains_key(&ino)
+            || self
+                .handles
+                .lock()
+                .unwrap()
+                .values()
+                .any(|handle| handle.ino == ino)
+    }
+
+    pub fn open(&self, handle: FileHandle) -> u64 {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, handle);
+        fh
+    }
+
+    /// Get handle `fh`, which must have been opened on `ino`.
+    pub fn get(&self, fh: u64, ino: u64) -> Result<FileHandle> {
+        match self.handles.lock().unwrap().get(&fh) {
+            Some(handle) if handle.ino == ino => Ok(*handle),
+            _ => Err(Error::BadFileHandle),
+        }
+    }
+
+    /// Record a write through `fh`.
+    pub fn mark_written(&self, fh: u64) {
+        if let Some(handle) = self.handles.lock().unwrap().get_mut(&fh) {
+            handle.dirty = true;
+        }
+    }
+
+    /// Mark all writers of `ino` dirty, returning whether there is any.
+    pub fn mark_dirty(&self, ino: u64) -> bool {
+        let mut found = false;
+        for handle in self.handles.lock().unwrap().values_mut() {
+            if handle.ino == ino && handle.write {
+                handle.dirty = true;
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Close `fh`, returning it and whether changes to its file must be
+    /// flushed now, that is it was the last dirty writer.
+    ///
+    /// Changes made through a writer closed before others are left to them.
+    pub fn close(&self, fh: u64, ino: u64) -> Result<(FileHandle, bool)> {
+        let mut handles = self.handles.lock().unwrap();
+        let handle = match handles.get(&fh) {
+            Some(handle) if handle.ino == ino => *handle,
+            _ => return Err(Error::BadFileHandle),
+        };
+        handles.remove(&fh);
+
+        let mut flush = handle.dirty;
+        for other in handles.values_mut() {
+            if other.ino == ino && other.write {
+                other.dirty |= handle.dirty;
+                flush = false;
+            }
+        }
+
+        Ok((handle, flush))
+    }
+}
@@ -1,38 +1,34 @@
+use crate::vfs::changelog::{self, MetadataSync};
+use crate::vfs::retry::RetryPolicy;
+use crate::vfs::store::RemoteStore;
 use crate::vfs::{Error, Result};
 
 use fuser::{FileAttr, FileType};
-use grammers_client::{
-    types::{Chat, Media, Message},
-    Client, InputMessage,
-};
-use sqlx::{FromRow, Pool, Row, Sqlite, SqlitePool};
+use sqlx::{sqlite::SqliteConnectOptions, FromRow, Pool, Row, Sqlite, SqlitePool, Transaction};
 use std::{
     ffi::OsStr,
+    path::Path,
     sync::Arc,
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
-use tokio::{
-    sync::{oneshot, Mutex},
-    time::{self, Instant},
-};
+use tokio::sync::{oneshot, Mutex};
 
 const BLOCK_SIZE: u32 = 512;
+pub(crate) const DB_VERSION: u32 = 6;
 
-const DB_CONN: &str = "sqlite://fuse.db?mode=rwc";
-const DB_FILE: &str = "fuse.db";
-const DB_TITLE: &str = "telegram-fuse db";
-const DB_UPLOAD_START: u64 = 30;
-const DB_UPLOAD_INTERVAL: u64 = 300;
-
+// SQLite only stores signed 64-bit integers.
 #[derive(Debug, Clone, FromRow)]
 pub struct InodeAttr {
-    pub ino: u32,
-    pub size: u32,
-    pub blocks: u32,
-    pub atime: u32,
-    pub mtime: u32,
-    pub ctime: u32,
-    pub crtime: u32,
+    #[sqlx(try_from = "i64")]
+    pub ino: u64,
+    #[sqlx(try_from = "i64")]
+    pub size: u64,
+    #[sqlx(try_from = "i64")]
+    pub blocks: u64,
+    pub atime: i64,
+    pub mtime: i64,
+    pub ctime: i64,
+    pub crtime: i64,
     pub kind: u16,
     pub perm: u16,
     pub nlink: u32,
@@ -41,20 +37,21 @@ pub struct InodeAttr {
     pub rdev: u32,
     pub blksize: u32,
     pub flags: u32,
-    pub remote_id: i32,
+    #[sqlx(try_from = "i64")]
+    pub chunk_size: u64,
     pub name: String,
 }
 
 impl InodeAttr {
     pub fn get_file_attr(&self) -> FileAttr {
         FileAttr {
-            ino: self.ino as u64,
-            size: self.size as u64,
-            blocks: self.blocks as u64,
-            atime: UNIX_EPOCH + Duration::from_secs(self.atime as u64),
-            mtime: UNIX_EPOCH + Duration::from_secs(self.mtime as u64),
-            ctime: UNIX_EPOCH + Duration::from_secs(self.ctime as u64),
-            crtime: UNIX_EPOCH + Duration::from_secs(self.crtime as u64),
+            ino: self.ino,
+            size: self.size,
+            blocks: self.blocks,
+            atime: system_time(self.atime),
+            mtime: system_time(self.mtime),
+            ctime: system_time(self.ctime),
+            crtime: system_time(self.crtime),
             kind: convert_file_type(self.kind.into()),
             perm: self.perm,
             nlink: self.nlink,
@@ -69,8 +66,8 @@ impl InodeAttr {
 
 #[derive(Debug, Clone, FromRow)]
 pub struct DirEntry {
-    pub parent_ino: u32,
-    pub child_ino: u32,
+    pub parent_ino: u64,
+    pub child_ino: u64,
     pub name: String,
     pub file_type: FileType,
 }
@@ -82,35 +79,51 @@ struct TaskChannel {
 
 pub struct InodeTree {
     db: Pool<Sqlite>,
-    client: Client,
-    chat: Chat,
+    sync: Arc<MetadataSync>,
     channel: Mutex<TaskChannel>,
+    chunk_size: u64,
 }
 
 impl InodeTree {
-    pub async fn new(client: Client, chat: Chat) -> anyhow::Result<Self> {
-        Self::fetch_db(&client, &chat).await?;
+    pub async fn new(
+        store: Arc<dyn RemoteStore>,
+        db_path: &Path,
+        chunk_size: u64,
+        incremental_sync: bool,
+        read_only: bool,
+        retry: RetryPolicy,
+    ) -> anyhow::Result<Self> {
+        let snapshot = MetadataSync::fetch_snapshot(store.as_ref(), db_path, &retry).await?;
 
         let (terminate_tx, terminate_rx) = oneshot::channel::<()>();
         let (done_tx, done_rx) = oneshot::channel::<()>();
 
-        let client_handle = Arc::new(client.clone());
-        let chat_handle = Arc::new(chat.clone());
-
+        let options = SqliteConnectOptions::new()
+            .filename(db_path)
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options).await?;
         let this = Self {
-            db: SqlitePool::connect(DB_CONN).await?,
-            client,
-            chat,
+            sync: Arc::new(MetadataSync::new(
+                db.clone(),
+                db_path,
+                store,
+                incremental_sync,
+                read_only,
+                retry,
+            )),
+            db,
             channel: Mutex::new(TaskChannel {
                 terminate_tx: Some(terminate_tx),
                 done_rx: Some(done_rx),
             }),
+            chunk_size,
         };
-        this.init().await?;
+        this.init(snapshot).await?;
 
+        let sync_handle = this.sync.clone();
         tokio::spawn(async move {
             tokio::select! {
-                _ = InodeTree::upload_task(client_handle, chat_handle) => {},
+                _ = sync_handle.run() => {},
                 _ = terminate_rx => {
                     log::info!("Exit upload task");
                     let _ = done_tx.send(());
@@ -130,31 +143,26 @@ impl InodeTree {
             let _ = rx.await;
         }
 
-        InodeTree::upload_db(&self.client, &self.chat).await?;
+        self.sync.upload().await?;
 
         Ok(())
     }
 
-    async fn upload_db(client: &Client, chat: &Chat) -> Result<()> {
-        let uploaded_file = client.upload_file(DB_FILE).await?;
+    /// Exchange metadata changes with the remote store now.
+    pub async fn sync(&self) -> Result<()> {
+        self.sync.sync().await
+    }
 
-        let message = InodeTree::get_db_message_id(client, chat).await?;
-        if let Some(msg) = message {
-            client
-                .edit_message(
-                    chat,
-                    msg.id(),
-                    InputMessage::text(DB_TITLE).file(uploaded_file),
-                )
-                .await?;
-        } else {
-            client
-                .send_message(chat, InputMessage::text(DB_TITLE).file(uploaded_file))
-                .await?;
-        }
-        log::info!("Upload {} to Telegram", DB_FILE);
+    /// Whether the metadata conflicts with changes from another device, see
+    /// `MetadataSync::is_diverged`.
+    pub fn is_diverged(&self) -> bool {
+        self.sync.is_diverged()
+    }
 
-        Ok(())
+    /// Remote ids of chunks changed by other devices since the last call,
+    /// see `MetadataSync::take_stale`.
+    pub fn take_stale(&self) -> Vec<i32> {
+        self.sync.take_stale()
     }
 
     pub async fn lookup(&self, parent_ino: u64, child_name: &OsStr) -> Result<Option<InodeAttr>> {
@@ -163,14 +171,14 @@ impl InodeTree {
         let sql = "
             SELECT
                 n.ino, n.size, n.blocks, n.atime, n.mtime, n.ctime, n.crtime, n.kind, n.perm,
-                n.nlink, n.uid, n.gid, n.rdev, n.blksize, n.flags, n.remote_id, nt.name
+                n.nlink, n.uid, n.gid, n.rdev, n.blksize, n.flags, n.chunk_size, nt.name
             FROM node_tree AS nt
                 INNER JOIN node AS n ON nt.child_ino = n.ino
             WHERE nt.parent_ino=$1 AND nt.name=$2
         ";
 
         let rec = sqlx::query_as(sql)
-            .bind(parent_ino as u32)
+            .bind(parent_ino as i64)
             .bind(child_name.to_str().unwrap())
             .fetch_optional(&mut conn)
             .await?;
@@ -184,14 +192,14 @@ impl InodeTree {
         let sql = "
             SELECT
                 n.ino, n.size, n.blocks, n.atime, n.mtime, n.ctime, n.crtime, n.kind, n.perm,
-                n.nlink, n.uid, n.gid, n.rdev, n.blksize, n.flags, n.remote_id, nt.name
+                n.nlink, n.uid, n.gid, n.rdev, n.blksize, n.flags, n.chunk_size, nt.name
             FROM node AS n
                 LEFT JOIN node_tree AS nt ON nt.child_ino = n.ino
             WHERE n.ino=$1
         ";
 
         let rec = sqlx::query_as(sql)
-            .bind(ino as u32)
+            .bind(ino as i64)
             .fetch_optional(&mut conn)
             .await?;
 
@@ -209,10 +217,10 @@ impl InodeTree {
         ";
 
         let recs = sqlx::query(sql)
-            .bind(ino as u32)
+            .bind(ino as i64)
             .map(|row| DirEntry {
-                parent_ino: ino as u32,
-                child_ino: row.get(0),
+                parent_ino: ino,
+                child_ino: row.get::<i64, _>(0) as u64,
                 file_type: convert_file_type(row.get(1)),
                 name: row.get(2),
             })
@@ -227,20 +235,54 @@ impl InodeTree {
         parent_ino: u64,
         name: &str,
         kind: FileType,
+        perm: u16,
         uid: u32,
         gid: u32,
-        remote_id: i32,
+    ) -> Result<InodeAttr> {
+        self.insert(parent_ino, name, kind, perm, uid, gid, None)
+            .await
+    }
+
+    /// Add a symbolic link, the target is kept in the database only.
+    pub async fn add_symlink(
+        &self,
+        parent_ino: u64,
+        name: &str,
+        target: &str,
+        uid: u32,
+        gid: u32,
+    ) -> Result<InodeAttr> {
+        self.insert(
+            parent_ino,
+            name,
+            FileType::Symlink,
+            0o777,
+            uid,
+            gid,
+            Some(target),
+        )
+        .await
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    async fn insert(
+        &self,
+        parent_ino: u64,
+        name: &str,
+        kind: FileType,
+        perm: u16,
+        uid: u32,
+        gid: u32,
+        target: Option<&str>,
     ) -> Result<InodeAttr> {
         let mut tx = self.db.begin().await?;
 
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
+        let time = unix_time(SystemTime::now());
+        let size = target.map_or(0, |target| target.len() as u64);
 
         let mut attr = InodeAttr {
             ino: 0,
-            size: 0,
+            size,
             blocks: 0,
             atime: time,
             mtime: time,
@@ -248,12 +290,10 @@ impl InodeTree {
             crtime: time,
             kind: match kind {
                 FileType::Directory => libc::S_IFDIR.try_into().unwrap(),
+                FileType::Symlink => libc::S_IFLNK.try_into().unwrap(),
                 _ => libc::S_IFREG.try_into().unwrap(),
             },
-            perm: match kind {
-                FileType::Directory => 0o777,
-                _ => 0o666,
-            },
+            perm,
             nlink: match kind {
                 FileType::Directory => 2,
                 _ => 1,
@@ -263,18 +303,23 @@ impl InodeTree {
             rdev: 0,
             blksize: BLOCK_SIZE,
             flags: 0,
-            remote_id,
+            chunk_size: match kind {
+                FileType::RegularFile => self.chunk_size,
+                _ => 0,
+            },
             name: String::from(name),
         };
 
         let node_sql = "
             INSERT INTO node (
-                atime, mtime, ctime, crtime, kind, perm, nlink, uid, gid, blksize, remote_id
+                size, atime, mtime, ctime, crtime, kind, perm, nlink, uid, gid, blksize,
+                chunk_size, target
             )
-            VALUES ($1, $1, $1, $1, $2, $3, $4, $5, $6, $7, $8)
+            VALUES ($1, $2, $2, $2, $2, $3, $4, $5, $6, $7, $8, $9, $10)
         ";
 
         let ino = sqlx::query(node_sql)
+            .bind(size as i64)
             .bind(time)
             .bind(attr.kind)
             .bind(attr.perm)
@@ -282,11 +327,12 @@ impl InodeTree {
             .bind(uid)
             .bind(gid)
             .bind(attr.blksize)
-            .bind(attr.remote_id)
+            .bind(attr.chunk_size as i64)
+            .bind(target)
             .execute(&mut tx)
             .await?
             .last_insert_rowid();
-        attr.ino = ino as u32;
+        attr.ino = ino as u64;
 
         let node_tree_sql = "
             INSERT INTO node_tree
@@ -294,7 +340,7 @@ impl InodeTree {
         ";
 
         sqlx::query(node_tree_sql)
-            .bind(parent_ino as u32)
+            .bind(parent_ino as i64)
             .bind(ino)
             .bind(attr.kind)
             .bind(name)
@@ -312,61 +358,53 @@ impl InodeTree {
         name: &OsStr,
         new_parent_ino: u64,
         new_name: &OsStr,
-    ) -> Result<Option<i32>> {
+    ) -> Result<Option<u64>> {
         if parent_ino == new_parent_ino && name == new_name {
             return Ok(None);
         }
 
-        let mut deleted_id = None;
+        let mut unlinked = None;
 
-        let old_entry = match self.get_dir(parent_ino as u32, name).await? {
+        let old_entry = match self.get_dir(parent_ino, name).await? {
             Some(e) => e,
             None => {
                 return Err(Error::NotFound);
             }
         };
-        let new_entry = self.get_dir(new_parent_ino as u32, new_name).await?;
+        let new_entry = self.get_dir(new_parent_ino, new_name).await?;
 
         if let Some(dest_entry) = &new_entry {
-            if dest_entry.file_type != old_entry.file_type {
-                match dest_entry.file_type {
-                    FileType::Directory => {
-                        return Err(Error::IsADirectory);
-                    }
-                    FileType::RegularFile => {
-                        return Err(Error::NotADirectory);
-                    }
-                    _ => {
-                        return Err(Error::InvalidFileType(dest_entry.file_type));
-                    }
-                }
+            // Both names are links to the same file, nothing to do.
+            if dest_entry.child_ino == old_entry.child_ino {
+                return Ok(None);
             }
-            if dest_entry.file_type == FileType::Directory {
-                if !self.is_directory_empty(dest_entry.child_ino as u64).await? {
-                    return Err(Error::DirectoryNotEmpty);
-                }
+            // Any non-directory may replace another.
+            match (old_entry.file_type, dest_entry.file_type) {
+                (FileType::Directory, FileType::Directory) => {}
+                (_, FileType::Directory) => return Err(Error::IsADirectory),
+                (FileType::Directory, _) => return Err(Error::NotADirectory),
+                _ => {}
             }
+            if dest_entry.file_type == FileType::Directory
+                && !self.is_directory_empty(dest_entry.child_ino).await?
+            {
+                return Err(Error::DirectoryNotEmpty);
+            }
+        }
 
-            let remote_id = match self.get(dest_entry.child_ino as u64).await? {
-                Some(attr) => attr.remote_id,
-                None => 0,
-            };
-
-            self.delete(
-                dest_entry.child_ino as u64,
+        // The destination is only gone once the source has taken its place.
+        let mut tx = self.db.begin().await?;
+        if let Some(dest_entry) = &new_entry {
+            unlinked = Self::unlink(
+                &mut tx,
+                dest_entry.child_ino,
                 dest_entry.parent_ino,
                 &dest_entry.name,
             )
             .await?;
-
-            if remote_id != 0 {
-                deleted_id = Some(remote_id);
-            }
         }
 
         {
-            let mut conn = self.db.acquire().await?;
-
             let sql = "
                 UPDATE node_tree
                 SET parent_ino=$3, name=$4
@@ -374,17 +412,14 @@ impl InodeTree {
             ";
 
             sqlx::query(sql)
-                .bind(old_entry.parent_ino)
+                .bind(old_entry.parent_ino as i64)
                 .bind(old_entry.name.clone())
-                .bind(new_parent_ino as u32)
+                .bind(new_parent_ino as i64)
                 .bind(new_name.to_str().unwrap())
-                .execute(&mut conn)
+                .execute(&mut tx)
                 .await?;
 
-            let time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32;
+            let time = unix_time(SystemTime::now());
 
             let sql = "
                 UPDATE node
@@ -392,9 +427,9 @@ impl InodeTree {
                 WHERE ino=$1
             ";
             sqlx::query(sql)
-                .bind(old_entry.child_ino)
+                .bind(old_entry.child_ino as i64)
                 .bind(time)
-                .execute(&mut conn)
+                .execute(&mut tx)
                 .await?;
 
             let sql = "
@@ -403,21 +438,211 @@ impl InodeTree {
                 WHERE ino=$1
             ";
             sqlx::query(sql)
-                .bind(old_entry.parent_ino)
+                .bind(old_entry.parent_ino as i64)
                 .bind(time)
-                .execute(&mut conn)
+                .execute(&mut tx)
                 .await?;
 
-            if old_entry.parent_ino != new_parent_ino as u32 {
+            if old_entry.parent_ino != new_parent_ino {
                 sqlx::query(sql)
-                    .bind(new_parent_ino as u32)
+                    .bind(new_parent_ino as i64)
                     .bind(time)
-                    .execute(&mut conn)
+                    .execute(&mut tx)
                     .await?;
             }
         }
+        tx.commit().await?;
+
+        Ok(unlinked)
+    }
+
+    /// Add `new_name` in `new_parent_ino` as another link to `ino`.
+    pub async fn link(&self, ino: u64, new_parent_ino: u64, new_name: &str) -> Result<InodeAttr> {
+        let mut tx = self.db.begin().await?;
+
+        let time = unix_time(SystemTime::now());
+
+        let node_sql = "
+            UPDATE node
+            SET nlink=nlink+1, ctime=$2
+            WHERE ino=$1
+            RETURNING kind
+        ";
+        let kind: u16 = sqlx::query_scalar(node_sql)
+            .bind(ino as i64)
+            .bind(time)
+            .fetch_optional(&mut tx)
+            .await?
+            .ok_or(Error::NotFound)?;
+        if u32::from(kind) == libc::S_IFDIR {
+            return Err(Error::NotPermitted);
+        }
+
+        let node_tree_sql = "
+            INSERT INTO node_tree
+            VALUES ($1, $2, $3, $4)
+        ";
+        sqlx::query(node_tree_sql)
+            .bind(new_parent_ino as i64)
+            .bind(ino as i64)
+            .bind(kind)
+            .bind(new_name)
+            .execute(&mut tx)
+            .await?;
 
-        Ok(deleted_id)
+        let parent_sql = "
+            UPDATE node
+            SET ctime=$2, mtime=$2
+            WHERE ino=$1
+        ";
+        sqlx::query(parent_sql)
+            .bind(new_parent_ino as i64)
+            .bind(time)
+            .execute(&mut tx)
+            .await?;
+
+        tx.commit().await?;
+
+        let mut attr = self.get(ino).await?.ok_or(Error::NotFound)?;
+        attr.name = String::from(new_name);
+
+        Ok(attr)
+    }
+
+    /// Get the target of a symbolic link.
+    pub async fn read_link(&self, ino: u64) -> Result<Option<String>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT target
+            FROM node
+            WHERE ino=$1 AND kind=$2
+        ";
+
+        let target = sqlx::query_scalar(sql)
+            .bind(ino as i64)
+            .bind(libc::S_IFLNK)
+            .fetch_optional(&mut conn)
+            .await?;
+
+        Ok(target)
+    }
+
+    pub async fn get_xattr(&self, ino: u64, name: &str) -> Result<Option<Vec<u8>>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT value
+            FROM xattr
+            WHERE ino=$1 AND name=$2
+        ";
+
+        let value = sqlx::query_scalar(sql)
+            .bind(ino as i64)
+            .bind(name)
+            .fetch_optional(&mut conn)
+            .await?;
+
+        Ok(value)
+    }
+
+    /// Names of the extended attributes of `ino`, in order.
+    pub async fn list_xattr(&self, ino: u64) -> Result<Vec<String>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT name
+            FROM xattr
+            WHERE ino=$1
+            ORDER BY name
+        ";
+
+        let names = sqlx::query_scalar(sql)
+            .bind(ino as i64)
+            .fetch_all(&mut conn)
+            .await?;
+
+        Ok(names)
+    }
+
+    /// Set an extended attribute, `flags` are `XATTR_CREATE` or `XATTR_REPLACE`.
+    pub async fn set_xattr(&self, ino: u64, name: &str, value: &[u8], flags: i32) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        let exists = sqlx::query("SELECT 1 FROM xattr WHERE ino=$1 AND name=$2")
+            .bind(ino as i64)
+            .bind(name)
+            .fetch_optional(&mut tx)
+            .await?
+            .is_some();
+        if exists && flags & libc::XATTR_CREATE != 0 {
+            return Err(Error::FileExists);
+        }
+        if !exists && flags & libc::XATTR_REPLACE != 0 {
+            return Err(Error::NoAttribute);
+        }
+
+        let sql = "
+            INSERT OR REPLACE INTO xattr (ino, name, value)
+            VALUES ($1, $2, $3)
+        ";
+        sqlx::query(sql)
+            .bind(ino as i64)
+            .bind(name)
+            .bind(value)
+            .execute(&mut tx)
+            .await?;
+
+        Self::touch(&mut tx, ino).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn remove_xattr(&self, ino: u64, name: &str) -> Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        let removed = sqlx::query("DELETE FROM xattr WHERE ino=$1 AND name=$2")
+            .bind(ino as i64)
+            .bind(name)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        if removed == 0 {
+            return Err(Error::NoAttribute);
+        }
+
+        Self::touch(&mut tx, ino).await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Update the change time of `ino`.
+    async fn touch(tx: &mut Transaction<'_, Sqlite>, ino: u64) -> Result<()> {
+        sqlx::query("UPDATE node SET ctime=$2 WHERE ino=$1")
+            .bind(ino as i64)
+            .bind(unix_time(SystemTime::now()))
+            .execute(tx)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Count used blocks and inodes.
+    pub async fn usage(&self) -> Result<(u64, u64)> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT COALESCE(SUM(blocks), 0), COUNT(*)
+            FROM node
+        ";
+
+        let row = sqlx::query(sql).fetch_one(&mut conn).await?;
+
+        Ok((row.get::<i64, _>(0) as u64, row.get::<i64, _>(1) as u64))
     }
 
     pub async fn is_directory_empty(&self, ino: u64) -> Result<bool> {
@@ -430,62 +655,266 @@ impl InodeTree {
         ";
 
         let rec = sqlx::query(sql)
-            .bind(ino as u32)
+            .bind(ino as i64)
             .fetch_optional(&mut conn)
             .await?;
 
-        if let Some(_) = rec {
-            Ok(false)
-        } else {
-            Ok(true)
-        }
+        Ok(rec.is_none())
     }
 
-    pub async fn delete(&self, ino: u64, parent_ino: u32, name: &str) -> Result<()> {
+    /// Remove the link `name` of `ino`, returning `ino` if it was the last one.
+    ///
+    /// An inode without links is kept until `purge`, so it stays usable while
+    /// open. A removed directory has no links left.
+    pub async fn delete(&self, ino: u64, parent_ino: u64, name: &str) -> Result<Option<u64>> {
         let mut tx = self.db.begin().await?;
+        let unlinked = Self::unlink(&mut tx, ino, parent_ino, name).await?;
+        tx.commit().await?;
 
+        Ok(unlinked)
+    }
+
+    /// `delete` within `tx`.
+    async fn unlink(
+        tx: &mut Transaction<'_, Sqlite>,
+        ino: u64,
+        parent_ino: u64,
+        name: &str,
+    ) -> Result<Option<u64>> {
         let node_tree_sql = "
             DELETE
             FROM node_tree
             WHERE parent_ino=$1 AND name=$2
         ";
         sqlx::query(node_tree_sql)
-            .bind(parent_ino)
+            .bind(parent_ino as i64)
             .bind(name)
-            .execute(&mut tx)
+            .execute(&mut *tx)
+            .await?;
+
+        let time = unix_time(SystemTime::now());
+
+        let update_node_sql = "
+            UPDATE node
+            SET ctime=$2, mtime=$2
+            WHERE ino=$1
+        ";
+        sqlx::query(update_node_sql)
+            .bind(parent_ino as i64)
+            .bind(time)
+            .execute(&mut *tx)
+            .await?;
+
+        let unlink_sql = "
+            UPDATE node
+            SET nlink=CASE WHEN kind=$3 THEN 0 ELSE nlink-1 END, ctime=$2
+            WHERE ino=$1
+            RETURNING nlink
+        ";
+        let nlink: u32 = sqlx::query_scalar(unlink_sql)
+            .bind(ino as i64)
+            .bind(time)
+            .bind(libc::S_IFDIR)
+            .fetch_one(&mut *tx)
             .await?;
 
+        if nlink == 0 {
+            // Other devices see the inode too, but leave it to this one.
+            let orphan_sql = "
+                INSERT OR IGNORE INTO orphan (ino)
+                VALUES ($1)
+            ";
+            sqlx::query(orphan_sql)
+                .bind(ino as i64)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        Ok((nlink == 0).then_some(ino))
+    }
+
+    /// Delete an inode left without links by this device, returning the
+    /// remote ids of its chunks.
+    ///
+    /// Another device may still have an inode it removed open, it is left to
+    /// that device.
+    pub async fn purge(&self, ino: u64) -> Result<Vec<i32>> {
+        let mut tx = self.db.begin().await?;
+
+        let orphan_sql = "
+            DELETE
+            FROM orphan
+            WHERE ino=$1
+        ";
+        let owned = sqlx::query(orphan_sql)
+            .bind(ino as i64)
+            .execute(&mut tx)
+            .await?
+            .rows_affected();
+        if owned == 0 {
+            return Ok(Vec::new());
+        }
+
         let node_sql = "
             DELETE
             FROM node
-            WHERE ino=$1
+            WHERE ino=$1 AND nlink=0
         ";
-        sqlx::query(node_sql)
-            .bind(ino as u32)
+        let deleted = sqlx::query(node_sql)
+            .bind(ino as i64)
             .execute(&mut tx)
+            .await?
+            .rows_affected();
+        if deleted == 0 {
+            tx.commit().await?;
+            return Ok(Vec::new());
+        }
+
+        let chunk_sql = "
+            DELETE
+            FROM chunk
+            WHERE ino=$1
+            RETURNING remote_id
+        ";
+        let remote_ids = sqlx::query_scalar(chunk_sql)
+            .bind(ino as i64)
+            .fetch_all(&mut tx)
             .await?;
 
-        let update_node_sql = "
-            UPDATE node
-            SET mtime=$2
+        let xattr_sql = "
+            DELETE
+            FROM xattr
             WHERE ino=$1
         ";
-        let time = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as u32;
-        sqlx::query(update_node_sql)
-            .bind(ino as u32)
-            .bind(time)
+        sqlx::query(xattr_sql)
+            .bind(ino as i64)
             .execute(&mut tx)
             .await?;
 
         tx.commit().await?;
 
+        Ok(remote_ids)
+    }
+
+    /// Inodes without links, left by files this device removed while open.
+    pub async fn orphans(&self) -> Result<Vec<u64>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT ino
+            FROM node
+            WHERE nlink=0 AND ino IN (SELECT ino FROM orphan)
+        ";
+
+        let recs = sqlx::query_scalar::<_, i64>(sql)
+            .fetch_all(&mut conn)
+            .await?;
+
+        Ok(recs.into_iter().map(|ino| ino as u64).collect())
+    }
+
+    /// Remote ids of all chunks of a file, in order.
+    pub async fn chunks(&self, ino: u64) -> Result<Vec<i32>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT remote_id
+            FROM chunk
+            WHERE ino=$1
+            ORDER BY idx
+        ";
+
+        let recs = sqlx::query_scalar(sql)
+            .bind(ino as i64)
+            .fetch_all(&mut conn)
+            .await?;
+
+        Ok(recs)
+    }
+
+    pub async fn get_chunk(&self, ino: u64, idx: u64) -> Result<Option<i32>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            SELECT remote_id
+            FROM chunk
+            WHERE ino=$1 AND idx=$2
+        ";
+
+        let rec = sqlx::query_scalar(sql)
+            .bind(ino as i64)
+            .bind(idx as i64)
+            .fetch_optional(&mut conn)
+            .await?;
+
+        Ok(rec)
+    }
+
+    pub async fn add_chunk(&self, ino: u64, idx: u64, remote_id: i32) -> Result<()> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            INSERT INTO chunk (ino, idx, remote_id)
+            VALUES ($1, $2, $3)
+        ";
+
+        sqlx::query(sql)
+            .bind(ino as i64)
+            .bind(idx as i64)
+            .bind(remote_id)
+            .execute(&mut conn)
+            .await?;
+
         Ok(())
     }
 
-    pub async fn update_attr(&self, ino: u64, size: u64, mtime: u32) -> Result<()> {
+    /// Remove chunks from `idx` on, returning their remote ids.
+    pub async fn truncate_chunks(&self, ino: u64, idx: u64) -> Result<Vec<i32>> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            DELETE
+            FROM chunk
+            WHERE ino=$1 AND idx>=$2
+            RETURNING remote_id
+        ";
+
+        let remote_ids = sqlx::query_scalar(sql)
+            .bind(ino as i64)
+            .bind(idx as i64)
+            .fetch_all(&mut conn)
+            .await?;
+
+        Ok(remote_ids)
+    }
+
+    /// Store the size, times, permissions and owner of `attr`.
+    pub async fn set_attr(&self, attr: &InodeAttr) -> Result<()> {
+        let mut conn = self.db.acquire().await?;
+
+        let sql = "
+            UPDATE node
+            SET size=$2, blocks=$3, atime=$4, mtime=$5, ctime=$6, perm=$7, uid=$8, gid=$9
+            WHERE ino=$1
+        ";
+
+        sqlx::query(sql)
+            .bind(attr.ino as i64)
+            .bind(attr.size as i64)
+            .bind(attr.blocks as i64)
+            .bind(attr.atime)
+            .bind(attr.mtime)
+            .bind(attr.ctime)
+            .bind(attr.perm)
+            .bind(attr.uid)
+            .bind(attr.gid)
+            .execute(&mut conn)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn update_attr(&self, ino: u64, size: u64, mtime: i64) -> Result<()> {
         let mut conn = self.db.acquire().await?;
 
         let sql = "
@@ -495,9 +924,9 @@ impl InodeTree {
         ";
 
         sqlx::query(sql)
-            .bind(ino as u32)
-            .bind(size as u32)
-            .bind((size as u32 + BLOCK_SIZE - 1) / BLOCK_SIZE)
+            .bind(ino as i64)
+            .bind(size as i64)
+            .bind(size.div_ceil(BLOCK_SIZE as u64) as i64)
             .bind(mtime)
             .execute(&mut conn)
             .await?;
@@ -505,7 +934,7 @@ impl InodeTree {
         Ok(())
     }
 
-    async fn get_dir(&self, parent_ino: u32, child_name: &OsStr) -> Result<Option<DirEntry>> {
+    async fn get_dir(&self, parent_ino: u64, child_name: &OsStr) -> Result<Option<DirEntry>> {
         let mut conn = self.db.acquire().await?;
 
         let sql = "
@@ -515,11 +944,11 @@ impl InodeTree {
         ";
 
         let rec = sqlx::query(sql)
-            .bind(parent_ino as u32)
+            .bind(parent_ino as i64)
             .bind(child_name.to_str().unwrap())
             .map(|row| DirEntry {
-                parent_ino: parent_ino,
-                child_ino: row.get(0),
+                parent_ino,
+                child_ino: row.get::<i64, _>(0) as u64,
                 file_type: convert_file_type(row.get(1)),
                 name: row.get(2),
             })
@@ -529,7 +958,9 @@ impl InodeTree {
         Ok(rec)
     }
 
-    async fn init(&self) -> anyhow::Result<()> {
+    /// Create or migrate the tables, then bring them up to date with the
+    /// remote change log, see `MetadataSync::replay`.
+    async fn init(&self, snapshot: Option<i32>) -> anyhow::Result<()> {
         let mut conn = self.db.acquire().await?;
 
         log::info!("Initialize meta tables");
@@ -569,6 +1000,14 @@ impl InodeTree {
             sqlx::query(sql).execute(&mut conn).await?;
         }
 
+        self.migrate().await?;
+        if snapshot.is_some() {
+            // Inodes removed while open by the device that uploaded it are
+            // left to that device.
+            sqlx::query("DELETE FROM orphan").execute(&mut conn).await?;
+        }
+        self.sync.replay(snapshot).await?;
+
         log::info!("Initialize meta data");
         {
             let sql = "
@@ -577,10 +1016,7 @@ impl InodeTree {
                 )
                 VALUES (1, $1, $1, $1, $1, $2, $3, 2, $4)
             ";
-            let time = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as u32;
+            let time = unix_time(SystemTime::now());
             sqlx::query(sql)
                 .bind(time)
                 .bind(libc::S_IFDIR)
@@ -593,40 +1029,138 @@ impl InodeTree {
         Ok(())
     }
 
-    async fn fetch_db(client: &Client, chat: &Chat) -> Result<()> {
-        let message = InodeTree::get_db_message_id(client, chat).await?;
-        if let Some(msg) = message {
-            client
-                .download_media(&msg.media().unwrap(), DB_FILE)
+    async fn migrate(&self) -> anyhow::Result<()> {
+        let mut tx = self.db.begin().await?;
+
+        let version: u32 = sqlx::query_scalar("PRAGMA user_version")
+            .fetch_one(&mut tx)
+            .await?;
+        // Written by a newer build, whose changes this one would not know.
+        if version > DB_VERSION {
+            anyhow::bail!(
+                "Metadata database schema version {} is newer than the supported {}, \
+                 upgrade telegram-fuse",
+                version,
+                DB_VERSION
+            );
+        }
+
+        if version < 1 {
+            log::info!("Migrate meta tables to chunked files");
+
+            let sql = "
+                CREATE TABLE chunk (
+                    ino INTEGER,
+                    idx INTEGER,
+                    remote_id INTEGER,
+                    PRIMARY KEY (ino, idx)
+                )
+            ";
+            sqlx::query(sql).execute(&mut tx).await?;
+
+            let sql = "ALTER TABLE node ADD COLUMN chunk_size INTEGER DEFAULT 0";
+            sqlx::query(sql).execute(&mut tx).await?;
+
+            // An existing file is a single document, keep it as the first chunk.
+            let sql = "
+                INSERT INTO chunk (ino, idx, remote_id)
+                SELECT ino, 0, remote_id
+                FROM node
+                WHERE kind=$1 AND remote_id!=0
+            ";
+            sqlx::query(sql)
+                .bind(libc::S_IFREG)
+                .execute(&mut tx)
+                .await?;
+
+            let sql = "
+                UPDATE node
+                SET chunk_size=MAX(size, $2), remote_id=0
+                WHERE kind=$1
+            ";
+            sqlx::query(sql)
+                .bind(libc::S_IFREG)
+                .bind(self.chunk_size as i64)
+                .execute(&mut tx)
                 .await?;
-            log::info!("Download {} from Telegram", DB_FILE);
         }
 
-        Ok(())
-    }
+        if version < 2 {
+            log::info!("Migrate meta tables to 64-bit sizes");
 
-    async fn get_db_message_id(client: &Client, chat: &Chat) -> Result<Option<Message>> {
-        let mut messages = client.search_messages(chat).query(DB_TITLE);
-        while let Some(message) = messages.next().await? {
-            if message.text() == DB_TITLE {
-                if let Some(Media::Document(document)) = message.media() {
-                    if document.name() == DB_FILE {
-                        return Ok(Some(message));
-                    }
-                }
-            }
+            // Columns are 64-bit already, only block counts computed with
+            // 32-bit arithmetic may have wrapped.
+            let sql = "
+                UPDATE node
+                SET blocks=(size + $1 - 1) / $1
+            ";
+            sqlx::query(sql).bind(BLOCK_SIZE).execute(&mut tx).await?;
         }
 
-        Ok(None)
-    }
+        if version < 3 {
+            log::info!("Migrate meta tables to symbolic links");
+
+            let sql = "ALTER TABLE node ADD COLUMN target TEXT";
+            sqlx::query(sql).execute(&mut tx).await?;
+        }
+
+        if version < 4 {
+            log::info!("Migrate meta tables to extended attributes");
+
+            let sql = "
+                CREATE TABLE xattr (
+                    ino INTEGER,
+                    name TEXT,
+                    value BLOB,
+                    PRIMARY KEY (ino, name)
+                )
+            ";
+            sqlx::query(sql).execute(&mut tx).await?;
+        }
+
+        if version < 5 {
+            log::info!("Migrate meta tables to change tracking");
+
+            changelog::create_tables(&mut tx).await?;
+        }
+
+        if version < 6 {
+            log::info!("Migrate meta tables to per-device orphans");
 
-    async fn upload_task(client: Arc<Client>, chat: Arc<Chat>) {
-        let start = Instant::now() + Duration::from_secs(DB_UPLOAD_START);
-        let mut interval = time::interval_at(start, Duration::from_secs(DB_UPLOAD_INTERVAL));
-        loop {
-            interval.tick().await;
-            let _ = InodeTree::upload_db(client.as_ref(), chat.as_ref()).await;
+            // Not logged, every device keeps its own.
+            let sql = "
+                CREATE TABLE orphan (
+                    ino INTEGER PRIMARY KEY
+                )
+            ";
+            sqlx::query(sql).execute(&mut tx).await?;
+        }
+
+        if version < DB_VERSION {
+            sqlx::query(&format!("PRAGMA user_version = {}", DB_VERSION))
+                .execute(&mut tx)
+                .await?;
         }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+/// Seconds since the Unix epoch, negative for earlier times.
+pub fn unix_time(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    }
+}
+
+fn system_time(secs: i64) -> SystemTime {
+    if secs >= 0 {
+        UNIX_EPOCH + Duration::from_secs(secs as u64)
+    } else {
+        UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
     }
 }
 
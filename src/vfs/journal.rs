@@ -0,0 +1,173 @@
+use crate::vfs::Result;
+
+use sqlx::{sqlite::SqliteConnectOptions, Pool, Row, Sqlite, SqlitePool};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+const QUEUE_FILE: &str = "queue.db";
+
+/// A dirty cache file waiting to be uploaded.
+#[derive(Debug)]
+pub struct PendingUpload {
+    pub remote_id: i32,
+    pub name: String,
+    pub path: PathBuf,
+}
+
+/// A clean cache file left by the last run.
+#[derive(Debug)]
+pub struct StoredFile {
+    pub remote_id: i32,
+    pub version: i64,
+    pub path: PathBuf,
+    pub size: u64,
+}
+
+/// Directory holding cache files, with a queue of those not uploaded yet.
+///
+/// Files matching a remote version are named `<remote_id>.<version>` and kept
+/// across restarts, any other name is temporary.
+///
+/// The queue lives next to the files rather than in the metadata database,
+/// since that one may be replaced by the remote copy at startup.
+pub struct Journal {
+    dir: PathBuf,
+    db: Pool<Sqlite>,
+}
+
+impl Journal {
+    pub async fn new(dir: &Path) -> anyhow::Result<Self> {
+        tokio::fs::create_dir_all(dir).await?;
+
+        let options = SqliteConnectOptions::new()
+            .filename(dir.join(QUEUE_FILE))
+            .create_if_missing(true);
+        let db = SqlitePool::connect_with(options).await?;
+
+        let sql = "
+            CREATE TABLE IF NOT EXISTS upload (
+                remote_id INTEGER PRIMARY KEY,
+                name TEXT,
+                path TEXT
+            )
+        ";
+        sqlx::query(sql).execute(&db).await?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            db,
+        })
+    }
+
+    /// Create a cache file with a temporary name.
+    pub fn create_file(&self, remote_id: i32) -> io::Result<(File, PathBuf)> {
+        let file = tempfile::Builder::new()
+            .prefix(&format!("{}-", remote_id))
+            .tempfile_in(&self.dir)?;
+
+        file.keep().map_err(|err| err.error)
+    }
+
+    /// Rename a cache file to a new temporary name.
+    pub fn rename_temporary(&self, remote_id: i32, path: &Path) -> io::Result<PathBuf> {
+        let (_, new_path) = self.create_file(remote_id)?;
+        std::fs::rename(path, &new_path)?;
+
+        Ok(new_path)
+    }
+
+    /// Rename a cache file to keep it as the content of `version`.
+    pub fn rename_stored(&self, remote_id: i32, version: i64, path: &Path) -> io::Result<PathBuf> {
+        let new_path = self.dir.join(format!("{}.{}", remote_id, version));
+        std::fs::rename(path, &new_path)?;
+
+        Ok(new_path)
+    }
+
+    /// Queue the upload of `path`, replacing an earlier one of `remote_id`.
+    pub async fn add(&self, remote_id: i32, name: &str, path: &Path) -> Result<()> {
+        let sql = "
+            INSERT OR REPLACE INTO upload (remote_id, name, path)
+            VALUES ($1, $2, $3)
+        ";
+        sqlx::query(sql)
+            .bind(remote_id)
+            .bind(name)
+            .bind(path.to_string_lossy())
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn remove(&self, remote_id: i32) -> Result<()> {
+        sqlx::query("DELETE FROM upload WHERE remote_id=$1")
+            .bind(remote_id)
+            .execute(&self.db)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Get the uploads and clean files left over by the last run, and delete
+    /// the other files it left behind.
+    ///
+    /// Stored files are ordered from the least recently modified.
+    pub async fn recover(&self) -> Result<(Vec<PendingUpload>, Vec<StoredFile>)> {
+        let mut pending = Vec::new();
+        for row in sqlx::query("SELECT remote_id, name, path FROM upload")
+            .fetch_all(&self.db)
+            .await?
+        {
+            let upload = PendingUpload {
+                remote_id: row.get(0),
+                name: row.get(1),
+                path: PathBuf::from(row.get::<String, _>(2)),
+            };
+            if upload.path.exists() {
+                pending.push(upload);
+            } else {
+                log::warn!("Lost cache file {:?} of {}", upload.path, upload.remote_id);
+                self.remove(upload.remote_id).await?;
+            }
+        }
+
+        let keep: HashSet<_> = pending.iter().map(|upload| upload.path.clone()).collect();
+        let mut stored = Vec::new();
+        let mut entries = tokio::fs::read_dir(&self.dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_str().unwrap_or_default();
+            if name.starts_with(QUEUE_FILE) || keep.contains(&path) {
+                continue;
+            }
+
+            let stored_key = name
+                .split_once('.')
+                .and_then(|(id, version)| Some((id.parse().ok()?, version.parse().ok()?)));
+            if let Some((remote_id, version)) = stored_key {
+                let metadata = entry.metadata().await?;
+                let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+                let file = StoredFile {
+                    remote_id,
+                    version,
+                    path,
+                    size: metadata.len(),
+                };
+                stored.push((modified, file));
+            } else {
+                log::debug!("Remove stale cache file {:?}", path);
+                tokio::fs::remove_file(&path).await?;
+            }
+        }
+        stored.sort_by_key(|(modified, _)| *modified);
+
+        Ok((pending, stored.into_iter().map(|(_, file)| file).collect()))
+    }
+}
@@ -0,0 +1,49 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex as SyncMutex};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+/// Locks by key, so that work on one key never waits for another.
+///
+/// A lock exists only while held or waited for.
+pub struct LockTable<K> {
+    locks: SyncMutex<HashMap<K, Arc<Mutex<()>>>>,
+}
+
+impl<K: Eq + Hash + Copy> LockTable<K> {
+    pub fn new() -> Self {
+        Self {
+            locks: SyncMutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait for the lock of `key`, held until the guard is dropped.
+    pub async fn lock(&self, key: K) -> LockGuard<'_, K> {
+        let lock = self.locks.lock().unwrap().entry(key).or_default().clone();
+        LockGuard {
+            table: self,
+            key,
+            guard: Some(lock.lock_owned().await),
+        }
+    }
+}
+
+pub struct LockGuard<'a, K: Eq + Hash + Copy> {
+    table: &'a LockTable<K>,
+    key: K,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl<K: Eq + Hash + Copy> Drop for LockGuard<'_, K> {
+    fn drop(&mut self) {
+        self.guard = None;
+        let mut locks = self.table.locks.lock().unwrap();
+        // Nobody else holds the lock or waits for it.
+        if locks
+            .get(&self.key)
+            .is_some_and(|lock| Arc::strong_count(lock) == 1)
+        {
+            locks.remove(&self.key);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use crate::vfs::store::{BlobInfo, BlobReader, RemoteStore};
+use crate::vfs::{Error, Result};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use grammers_client::types::iter_buffer::InvocationError;
+use grammers_tl_types as tl;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const CHUNK_SIZE: usize = 512 * 1024;
+
+struct Document {
+    name: String,
+    caption: String,
+    version: i64,
+    data: Bytes,
+}
+
+impl Document {
+    fn info(&self, id: i32) -> BlobInfo {
+        BlobInfo {
+            id,
+            name: self.name.clone(),
+            caption: self.caption.clone(),
+            size: self.data.len() as u64,
+            version: self.version,
+        }
+    }
+}
+
+struct Messages {
+    last_id: i32,
+    last_version: i64,
+    failures: usize,
+    /// Code and message of the RPC error injected failures look like, if any.
+    failure_rpc: Option<(i32, String)>,
+    /// New blobs to store without answering.
+    unanswered: usize,
+    fetches: usize,
+    replaces: usize,
+    documents: BTreeMap<i32, Document>,
+}
+
+/// In-process remote store behaving like a chat of document messages.
+///
+/// Ids are assigned in increasing order like Telegram message ids, and deleted
+/// ids are never reused.
+pub struct MemoryStore {
+    messages: Mutex<Messages>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self {
+            messages: Mutex::new(Messages {
+                last_id: 0,
+                last_version: 0,
+                failures: 0,
+                failure_rpc: None,
+                unanswered: 0,
+                fetches: 0,
+                replaces: 0,
+                documents: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Number of stored blobs, including the metadata database.
+    pub fn len(&self) -> usize {
+        self.messages.lock().unwrap().documents.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Make the next `count` uploads fail.
+    pub fn fail_uploads(&self, count: usize) {
+        let mut messages = self.messages.lock().unwrap();
+        messages.failures = count;
+        messages.failure_rpc = None;
+    }
+
+    /// Make the next `count` uploads fail with the RPC error `message`, e.g.
+    /// `FLOOD_WAIT_5`, wrapped in an IO error like grammers does for parts.
+    pub fn fail_uploads_rpc(&self, count: usize, code: i32, message: &str) {
+        let mut messages = self.messages.lock().unwrap();
+        messages.failures = count;
+        messages.failure_rpc = Some((code, message.to_owned()));
+    }
+
+    /// Store the next `count` new blobs, but fail like a connection lost
+    /// before the answer.
+    pub fn drop_upload_answers(&self, count: usize) {
+        self.messages.lock().unwrap().unanswered = count;
+    }
+
+    fn check_failure(&self) -> Result<()> {
+        let mut messages = self.messages.lock().unwrap();
+        if messages.failures > 0 {
+            messages.failures -= 1;
+            return Err(Error::Io(match &messages.failure_rpc {
+                Some((code, message)) => io::Error::other(InvocationError::Rpc(
+                    tl::types::RpcError {
+                        error_code: *code,
+                        error_message: message.clone(),
+                    }
+                    .into(),
+                )),
+                None => io::Error::other("Injected upload failure"),
+            }));
+        }
+        Ok(())
+    }
+
+    /// Number of blob downloads so far.
+    pub fn fetches(&self) -> usize {
+        self.messages.lock().unwrap().fetches
+    }
+
+    /// Number of blobs replaced so far, like file contents are uploaded.
+    pub fn replaces(&self) -> usize {
+        self.messages.lock().unwrap().replaces
+    }
+
+    /// Get the whole content of a blob.
+    pub fn data(&self, id: i32) -> Option<Bytes> {
+        let messages = self.messages.lock().unwrap();
+        messages.documents.get(&id).map(|doc| doc.data.clone())
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+async fn read_blob(reader: &mut (dyn AsyncRead + Unpin + Send), size: usize) -> Result<Bytes> {
+    let mut buf = vec![0u8; size];
+    reader.read_exact(&mut buf).await?;
+    Ok(buf.into())
+}
+
+struct MemoryBlobReader {
+    data: Bytes,
+}
+
+#[async_trait]
+impl BlobReader for MemoryBlobReader {
+    async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+        let len = self.data.len().min(CHUNK_SIZE);
+        Ok(Some(self.data.split_to(len).to_vec()))
+    }
+}
+
+#[async_trait]
+impl RemoteStore for MemoryStore {
+    async fn put(
+        &self,
+        caption: &str,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<i32> {
+        self.check_failure()?;
+        let data = read_blob(reader, size).await?;
+
+        let mut messages = self.messages.lock().unwrap();
+        messages.last_id += 1;
+        messages.last_version += 1;
+        let id = messages.last_id;
+        let version = messages.last_version;
+        messages.documents.insert(
+            id,
+            Document {
+                name: String::from(name),
+                caption: String::from(caption),
+                version,
+                data,
+            },
+        );
+        if messages.unanswered > 0 {
+            messages.unanswered -= 1;
+            return Err(Error::Grammers(InvocationError::Dropped));
+        }
+
+        Ok(id)
+    }
+
+    async fn replace(
+        &self,
+        id: i32,
+        caption: &str,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<()> {
+        self.check_failure()?;
+        let data = read_blob(reader, size).await?;
+
+        let mut messages = self.messages.lock().unwrap();
+        messages.replaces += 1;
+        messages.last_version += 1;
+        let version = messages.last_version;
+        let doc = messages.documents.get_mut(&id).ok_or(Error::NotFound)?;
+        doc.version = version;
+        doc.name = String::from(name);
+        doc.caption = String::from(caption);
+        doc.data = data;
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: i32) -> Result<Option<BlobInfo>> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages.documents.get(&id).map(|doc| doc.info(id)))
+    }
+
+    async fn fetch(&self, id: i32) -> Result<Box<dyn BlobReader>> {
+        self.messages.lock().unwrap().fetches += 1;
+        let data = self.data(id).ok_or(Error::NotFound)?;
+        Ok(Box::new(MemoryBlobReader { data }))
+    }
+
+    async fn delete(&self, id: i32) -> Result<()> {
+        self.messages.lock().unwrap().documents.remove(&id);
+        Ok(())
+    }
+
+    async fn list(&self, caption: &str) -> Result<Vec<BlobInfo>> {
+        let messages = self.messages.lock().unwrap();
+        Ok(messages
+            .documents
+            .iter()
+            .filter(|(_, doc)| doc.caption == caption)
+            .map(|(&id, doc)| doc.info(id))
+            .collect())
+    }
+}
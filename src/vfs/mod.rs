@@ -1,41 +1,145 @@
+use bytes::{Bytes, BytesMut};
 use fuser::FileType;
-use grammers_client::types::Chat;
-use grammers_client::Client;
 use std::ffi::OsStr;
+use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::SystemTime;
 
+mod changelog;
 mod error;
 mod file;
+mod handle;
 mod inode;
-
-use error::{Error, Result};
+mod journal;
+mod lock;
+mod memory;
+pub mod offline;
+mod retry;
+mod store;
+mod telegram;
+
+pub use changelog::DB_FILE;
+pub use error::{Error, Result};
 use file::FileCache;
-use inode::{DirEntry, InodeAttr, InodeTree};
+use handle::{FileHandle, HandleTable};
+use inode::{unix_time, InodeTree};
+pub use inode::{DirEntry, InodeAttr};
+use journal::Journal;
+use lock::LockTable;
+pub use memory::MemoryStore;
+pub use retry::RetryPolicy;
+pub use store::{BlobInfo, BlobReader, RemoteStore};
+pub use telegram::TelegramStore;
+
+const DEFAULT_CHUNK_SIZE: u64 = 128 << 20;
+const DEFAULT_CACHE_DIR: &str = "cache";
+const DEFAULT_CACHE_SIZE: u64 = 1 << 30;
+const DEFAULT_CAPACITY: u64 = 1 << 40;
+const BLOCK_SIZE: u64 = 512;
+const MAX_FILES: u64 = u32::MAX as u64;
+
+pub struct Config {
+    /// Local path of the metadata database.
+    pub db_path: PathBuf,
+    /// Directory of cached file contents and their pending uploads.
+    pub cache_dir: PathBuf,
+    /// Bytes of clean file contents to keep cached.
+    pub cache_size: u64,
+    /// Total space reported to `statfs`, Telegram itself has no quota.
+    pub capacity: u64,
+    /// Size of the remote documents new files are split into.
+    pub chunk_size: u64,
+    pub async_flush: bool,
+    /// Upload metadata changes as small change-log messages, compacted into
+    /// a snapshot of the whole database from time to time.
+    pub incremental_sync: bool,
+    /// Refuse all changes, and never upload or delete anything remotely.
+    pub read_only: bool,
+    /// Retrying of failed remote operations, for files and metadata.
+    pub upload_retry: RetryPolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            db_path: PathBuf::from(changelog::DB_FILE),
+            cache_dir: PathBuf::from(DEFAULT_CACHE_DIR),
+            cache_size: DEFAULT_CACHE_SIZE,
+            capacity: DEFAULT_CAPACITY,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            async_flush: false,
+            incremental_sync: true,
+            read_only: false,
+            upload_retry: RetryPolicy::default(),
+        }
+    }
+}
 
 pub struct Vfs {
     inode_tree: InodeTree,
     cache: file::DiskCache,
+    // Serializes opening each chunk so concurrent writes don't allocate one twice.
+    chunk_locks: LockTable<(u64, u64)>,
+    // Serializes size changes of each file, so concurrent appends never
+    // compute the same end.
+    inode_locks: LockTable<u64>,
     async_flush: bool,
+    capacity: u64,
+    read_only: bool,
+    handles: HandleTable,
+}
+
+/// Attributes to change in `set_attr`, `None` keeps the current value.
+#[derive(Debug, Clone, Default)]
+pub struct SetAttr {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub size: Option<u64>,
+    pub atime: Option<SystemTime>,
+    pub mtime: Option<SystemTime>,
+}
+
+/// Filesystem usage, sizes are in 512-byte blocks.
+#[derive(Debug, Clone)]
+pub struct StatFs {
+    pub blocks: u64,
+    pub free_blocks: u64,
+    pub files: u64,
+    pub free_files: u64,
 }
 
 impl Vfs {
-    pub async fn new(
-        client: Client,
-        chat_id: Option<i64>,
-        async_flush: bool,
-    ) -> anyhow::Result<Arc<Self>> {
-        if let Some(chat) = Vfs::get_chat(client.clone(), chat_id).await? {
-            let this = Arc::new(Self {
-                inode_tree: InodeTree::new(client.clone(), chat.clone()).await?,
-                cache: file::DiskCache::new(client.clone(), chat.clone()),
-                async_flush,
-            });
-
-            Ok(this)
-        } else {
-            panic!("Chat not found");
+    pub async fn new(store: Arc<dyn RemoteStore>, config: Config) -> anyhow::Result<Arc<Self>> {
+        let journal = Journal::new(&config.cache_dir).await?;
+        let this = Arc::new(Self {
+            inode_tree: InodeTree::new(
+                store.clone(),
+                &config.db_path,
+                config.chunk_size,
+                config.incremental_sync,
+                config.read_only,
+                config.upload_retry.clone(),
+            )
+            .await?,
+            cache: file::DiskCache::new(store, config.upload_retry, journal, config.cache_size),
+            chunk_locks: LockTable::new(),
+            inode_locks: LockTable::new(),
+            async_flush: config.async_flush,
+            capacity: config.capacity,
+            read_only: config.read_only,
+            handles: HandleTable::new(),
+        });
+        // Pending uploads and removals wait for the next writable mount.
+        if !this.read_only {
+            this.cache.resume().await?;
+            for ino in this.inode_tree.orphans().await? {
+                log::info!("Delete inode {} removed while open", ino);
+                this.release(ino).await?;
+            }
         }
+
+        Ok(this)
     }
 
     pub async fn lookup(&self, parent_ino: u64, child_name: &OsStr) -> Result<InodeAttr> {
@@ -43,6 +147,7 @@ impl Vfs {
 
         if let Some(v) = attr {
             log::trace!(target: "vfs::inode", "lookup: ino={} attr={:?}", v.ino, v);
+            self.handles.lookup(v.ino);
             Ok(v)
         } else {
             Err(Error::NotFound)
@@ -50,8 +155,10 @@ impl Vfs {
     }
 
     pub async fn forget(&self, ino: u64, count: u64) -> Result<()> {
-        // TODO:
         log::trace!(target: "vfs::inode", "forget: ino={} count={}", ino, count);
+        if self.handles.forget(ino, count) {
+            self.release(ino).await?;
+        }
         Ok(())
     }
 
@@ -66,93 +173,192 @@ impl Vfs {
         }
     }
 
+    /// Get an extended attribute, failing if it is longer than a non-zero `size`.
+    pub async fn get_xattr(&self, ino: u64, name: &OsStr, size: u32) -> Result<Vec<u8>> {
+        let name = name.to_str().ok_or(Error::NoAttribute)?;
+        let value = self
+            .inode_tree
+            .get_xattr(ino, name)
+            .await?
+            .ok_or(Error::NoAttribute)?;
+        log::trace!(target: "vfs::inode", "get_xattr: ino={} name={}", ino, name);
+
+        check_xattr_size(value, size)
+    }
+
+    /// Get the null-terminated names of all extended attributes, failing if
+    /// they are longer than a non-zero `size`.
+    pub async fn list_xattr(&self, ino: u64, size: u32) -> Result<Vec<u8>> {
+        self.get_attr(ino).await?;
+
+        let mut names = Vec::new();
+        for name in self.inode_tree.list_xattr(ino).await? {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        log::trace!(target: "vfs::inode", "list_xattr: ino={}", ino);
+
+        check_xattr_size(names, size)
+    }
+
+    pub async fn set_xattr(&self, ino: u64, name: &OsStr, value: &[u8], flags: i32) -> Result<()> {
+        self.check_writable()?;
+        self.get_attr(ino).await?;
+
+        let name = name.to_str().ok_or(Error::InvalidArgument)?;
+        self.inode_tree.set_xattr(ino, name, value, flags).await?;
+        log::trace!(
+            target: "vfs::inode",
+            "set_xattr: ino={} name={} len={} flags={:#x}",
+            ino, name, value.len(), flags,
+        );
+
+        Ok(())
+    }
+
+    pub async fn remove_xattr(&self, ino: u64, name: &OsStr) -> Result<()> {
+        self.check_writable()?;
+        let name = name.to_str().ok_or(Error::NoAttribute)?;
+        self.inode_tree.remove_xattr(ino, name).await?;
+        log::trace!(target: "vfs::inode", "remove_xattr: ino={} name={}", ino, name);
+
+        Ok(())
+    }
+
+    pub async fn statfs(&self) -> Result<StatFs> {
+        let (used_blocks, files) = self.inode_tree.usage().await?;
+        let blocks = self.capacity / BLOCK_SIZE;
+        let stat = StatFs {
+            blocks,
+            free_blocks: blocks.saturating_sub(used_blocks),
+            files,
+            free_files: MAX_FILES.saturating_sub(files),
+        };
+        log::trace!(target: "vfs::inode", "statfs: {:?}", stat);
+
+        Ok(stat)
+    }
+
     pub async fn open_dir(&self, ino: u64) -> Result<u64> {
-        log::trace!(target: "vfs::dir", "open_dir: ino={}", ino);
-        Ok(0)
+        let attr = self.get_attr(ino).await?;
+        if u32::from(attr.kind) != libc::S_IFDIR {
+            return Err(Error::NotADirectory);
+        }
+
+        let fh = self.handles.open(FileHandle::new(ino, libc::O_RDONLY));
+        log::trace!(target: "vfs::dir", "open_dir: ino={} fh={}", ino, fh);
+        Ok(fh)
     }
 
-    pub async fn close_dir(&self, ino: u64, _fh: u64) -> Result<()> {
-        log::trace!(target: "vfs::dir", "close_dir: ino={}", ino);
+    pub async fn close_dir(&self, ino: u64, fh: u64) -> Result<()> {
+        self.handles.close(fh, ino)?;
+        log::trace!(target: "vfs::dir", "close_dir: ino={} fh={}", ino, fh);
         Ok(())
     }
 
-    pub async fn read_dir(
-        &self,
-        ino: u64,
-        _fh: u64,
-        offset: i64,
-    ) -> Result<impl AsRef<[DirEntry]>> {
+    pub async fn read_dir(&self, ino: u64, fh: u64, offset: i64) -> Result<impl AsRef<[DirEntry]>> {
+        self.handles.get(fh, ino)?;
         let ret = self.inode_tree.read_dir(ino).await?;
         log::trace!(target: "vfs::dir", "read_dir: ino={} offset={}", ino, offset);
         Ok(ret)
     }
 
-    pub async fn open_file(&self, ino: u64, _write: bool) -> Result<u64> {
-        if let Some(attr) = self.inode_tree.get(ino).await? {
-            let fh = self.cache.open(attr.remote_id).await?;
-            log::trace!(target: "vfs::file", "open_file: ino={} fh={}", ino, fh);
+    /// Open a file with the access mode and `O_TRUNC`/`O_APPEND` of `flags`.
+    pub async fn open_file(&self, ino: u64, flags: i32) -> Result<u64> {
+        if self.inode_tree.get(ino).await?.is_some() {
+            // Chunks are fetched on demand by reads and writes.
+            let handle = FileHandle::new(ino, flags);
+            if handle.write || flags & libc::O_TRUNC != 0 {
+                self.check_writable()?;
+            }
+            let fh = self.handles.open(handle);
+
+            if handle.write && flags & libc::O_TRUNC != 0 {
+                let set = SetAttr {
+                    size: Some(0),
+                    ..Default::default()
+                };
+                if let Err(err) = self.set_attr(ino, set).await {
+                    self.handles.close(fh, ino)?;
+                    return Err(err);
+                }
+            }
+
+            log::trace!(
+                target: "vfs::file",
+                "open_file: ino={} fh={} handle={:?}",
+                ino, fh, handle,
+            );
             Ok(fh)
         } else {
             Err(Error::MediaInvalid)
         }
     }
 
+    /// Create and open a file, or open the existing one unless `O_EXCL` is set.
     pub async fn open_create_file(
         &self,
         parent_ino: u64,
         child_name: &OsStr,
+        perm: u16,
         uid: u32,
         gid: u32,
-        truncate: bool,
-        exclusive: bool,
-    ) -> Result<InodeAttr> {
+        flags: i32,
+    ) -> Result<(InodeAttr, u64)> {
         let lookup_result = self.inode_tree.lookup(parent_ino, child_name).await?;
         let name = child_name.to_str().unwrap();
-        let attr: InodeAttr;
-        match lookup_result {
+        let (attr, fh) = match lookup_result {
             None => {
-                let (_, remote_id) = self.cache.open_create_empty(name).await?;
-
-                attr = self
+                self.check_writable()?;
+                let attr = self
                     .inode_tree
-                    .add(parent_ino, name, FileType::RegularFile, uid, gid, remote_id)
+                    .add(parent_ino, name, FileType::RegularFile, perm, uid, gid)
                     .await?;
+                let fh = self.open_file(attr.ino, flags & !libc::O_TRUNC).await?;
+                (attr, fh)
+            }
+            Some(_) if flags & libc::O_EXCL != 0 => {
+                return Err(Error::FileExists);
             }
             Some(v) => {
-                if !truncate {
-                    if exclusive {
-                        return Err(Error::FileExists);
-                    }
-
-                    self.open_file(v.ino as u64, true).await?;
-
-                    return Ok(v);
-                }
-                attr = v;
+                let fh = self.open_file(v.ino, flags).await?;
+                (self.get_attr(v.ino).await?, fh)
             }
-        }
+        };
 
         log::trace!(
             target: "vfs::file",
-            "open_create_file: ino={} name={}",
+            "open_create_file: ino={} name={} fh={}",
             attr.ino,
-            child_name.to_str().unwrap(),
+            name,
+            fh,
         );
+        self.handles.lookup(attr.ino);
 
-        Ok(attr)
+        Ok((attr, fh))
     }
 
+    /// Close a file handle, uploading the changes if it was the last writer.
     pub async fn close_file(&self, ino: u64, fh: u64) -> Result<()> {
-        if let Some(attr) = self.inode_tree.get(ino).await? {
-            self.cache
-                .flush(attr.remote_id, &attr.name, !self.async_flush)
-                .await?;
-            log::trace!(target: "vfs::file", "close_file: ino={} fh={}", ino, fh);
+        let (handle, flush) = self.handles.close(fh, ino)?;
+        log::trace!(
+            target: "vfs::file",
+            "close_file: ino={} fh={} handle={:?} flush={}",
+            ino, fh, handle, flush,
+        );
 
-            Ok(())
-        } else {
-            Err(Error::NotFound)
+        match self.inode_tree.get(ino).await? {
+            // A removed file has nothing worth uploading.
+            Some(attr) if attr.nlink == 0 => self.release(ino).await?,
+            Some(attr) if flush => {
+                self.flush_chunks(ino, &attr.name, !self.async_flush)
+                    .await?
+            }
+            _ => {}
         }
+        self.cache.evict();
+
+        Ok(())
     }
 
     pub async fn read_file(
@@ -162,24 +368,47 @@ impl Vfs {
         offset: u64,
         size: usize,
     ) -> Result<impl AsRef<[u8]>> {
+        if !self.handles.get(fh, ino)?.read {
+            return Err(Error::BadFileHandle);
+        }
+        self.drop_stale().await;
+
         if let Some(attr) = self.inode_tree.get(ino).await? {
-            if let Some(file) = self.cache.get(&attr.remote_id) {
-                let ret = FileCache::read(&file, offset, size).await?;
-                log::trace!(
-                    target: "vfs::file",
-                    "read_file: ino={} fh={} offset={} size={} bytes_read={}",
-                    ino,
-                    fh,
-                    offset,
-                    size,
-                    ret.as_ref().len(),
-                );
-                Ok(ret)
-            } else {
-                Err(Error::NotFound)
+            let chunk_size = attr.chunk_size;
+            let end = (offset + size as u64).min(attr.size);
+
+            let mut buf = BytesMut::new();
+            let mut pos = offset;
+            while pos < end {
+                let idx = pos / chunk_size;
+                let chunk_start = idx * chunk_size;
+                let chunk_end = (chunk_start + chunk_size).min(end);
+
+                if let Some(remote_id) = self.inode_tree.get_chunk(ino, idx).await? {
+                    let file = self.cache.open(remote_id).await?;
+                    let data =
+                        FileCache::read(&file, pos - chunk_start, (chunk_end - pos) as usize)
+                            .await?;
+                    buf.extend_from_slice(&data);
+                }
+                // Holes and the space after a short chunk read as zeros.
+                buf.resize((chunk_end - offset) as usize, 0);
+                pos = chunk_end;
             }
+            let ret: Bytes = buf.freeze();
+
+            log::trace!(
+                target: "vfs::file",
+                "read_file: ino={} fh={} offset={} size={} bytes_read={}",
+                ino,
+                fh,
+                offset,
+                size,
+                ret.len(),
+            );
+            Ok(ret)
         } else {
-            Err(Error::NotFound)
+            Err(Error::Stale)
         }
     }
 
@@ -187,9 +416,11 @@ impl Vfs {
         &self,
         parent_ino: u64,
         name: &OsStr,
+        perm: u16,
         uid: u32,
         gid: u32,
     ) -> Result<InodeAttr> {
+        self.check_writable()?;
         let lookup_result = self.inode_tree.lookup(parent_ino, name).await?;
 
         let name = name.to_str().unwrap();
@@ -197,19 +428,81 @@ impl Vfs {
             None => {
                 let attr = self
                     .inode_tree
-                    .add(parent_ino, name, FileType::Directory, uid, gid, 0)
+                    .add(parent_ino, name, FileType::Directory, perm, uid, gid)
                     .await?;
                 log::trace!(
                     target: "vfs::dir",
                     "create_dir: parent_ino={} name={} ino={}",
                     parent_ino, name, attr.ino,
                 );
+                self.handles.lookup(attr.ino);
                 Ok(attr)
             }
             Some(_) => Err(Error::FileExists),
         }
     }
 
+    pub async fn create_symlink(
+        &self,
+        parent_ino: u64,
+        name: &OsStr,
+        target: &OsStr,
+        uid: u32,
+        gid: u32,
+    ) -> Result<InodeAttr> {
+        self.check_writable()?;
+        if self.inode_tree.lookup(parent_ino, name).await?.is_some() {
+            return Err(Error::FileExists);
+        }
+
+        let name = name.to_str().unwrap();
+        let target = target.to_str().ok_or(Error::InvalidArgument)?;
+        let attr = self
+            .inode_tree
+            .add_symlink(parent_ino, name, target, uid, gid)
+            .await?;
+        log::trace!(
+            target: "vfs::dir",
+            "create_symlink: parent_ino={} name={} target={} ino={}",
+            parent_ino, name, target, attr.ino,
+        );
+        self.handles.lookup(attr.ino);
+
+        Ok(attr)
+    }
+
+    pub async fn read_link(&self, ino: u64) -> Result<String> {
+        log::trace!(target: "vfs::inode", "read_link: ino={}", ino);
+        match self.inode_tree.read_link(ino).await? {
+            Some(target) => Ok(target),
+            None if self.inode_tree.get(ino).await?.is_some() => Err(Error::InvalidArgument),
+            None => Err(Error::NotFound),
+        }
+    }
+
+    pub async fn link(&self, ino: u64, new_parent_ino: u64, new_name: &OsStr) -> Result<InodeAttr> {
+        self.check_writable()?;
+        if self
+            .inode_tree
+            .lookup(new_parent_ino, new_name)
+            .await?
+            .is_some()
+        {
+            return Err(Error::FileExists);
+        }
+
+        let new_name = new_name.to_str().unwrap();
+        let attr = self.inode_tree.link(ino, new_parent_ino, new_name).await?;
+        log::trace!(
+            target: "vfs::dir",
+            "link: ino={} new_parent_ino={} new_name={} nlink={}",
+            ino, new_parent_ino, new_name, attr.nlink,
+        );
+        self.handles.lookup(attr.ino);
+
+        Ok(attr)
+    }
+
     pub async fn rename(
         &self,
         parent_ino: u64,
@@ -217,12 +510,13 @@ impl Vfs {
         new_parent_ino: u64,
         new_name: &OsStr,
     ) -> Result<()> {
-        if let Some(remote_id) = self
+        self.check_writable()?;
+        if let Some(ino) = self
             .inode_tree
             .rename(parent_ino, name, new_parent_ino, new_name)
             .await?
         {
-            self.cache.delete(remote_id).await?;
+            self.release(ino).await?;
         }
 
         log::debug!(
@@ -237,19 +531,20 @@ impl Vfs {
     }
 
     pub async fn remove_dir(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
+        self.check_writable()?;
         let lookup_result = self.inode_tree.lookup(parent_ino, name).await?;
         let name = name.to_str().unwrap();
 
         match lookup_result {
             None => Err(Error::NotFound),
             Some(attr) => {
-                if !self.inode_tree.is_directory_empty(attr.ino as u64).await? {
+                if !self.inode_tree.is_directory_empty(attr.ino).await? {
                     return Err(Error::DirectoryNotEmpty);
                 }
 
-                self.inode_tree
-                    .delete(attr.ino as u64, parent_ino as u32, name)
-                    .await?;
+                if let Some(ino) = self.inode_tree.delete(attr.ino, parent_ino, name).await? {
+                    self.release(ino).await?;
+                }
 
                 log::trace!(
                     target: "vfs::dir",
@@ -263,16 +558,16 @@ impl Vfs {
     }
 
     pub async fn remove_file(&self, parent_ino: u64, name: &OsStr) -> Result<()> {
+        self.check_writable()?;
         let lookup_result = self.inode_tree.lookup(parent_ino, name).await?;
         let name = name.to_str().unwrap();
 
         match lookup_result {
             None => Err(Error::NotFound),
             Some(attr) => {
-                self.cache.delete(attr.remote_id).await?;
-                self.inode_tree
-                    .delete(attr.ino as u64, parent_ino as u32, name)
-                    .await?;
+                if let Some(ino) = self.inode_tree.delete(attr.ino, parent_ino, name).await? {
+                    self.release(ino).await?;
+                }
 
                 log::trace!(
                     target: "vfs::dir",
@@ -286,9 +581,34 @@ impl Vfs {
     }
 
     pub async fn write_file(&self, ino: u64, fh: u64, offset: u64, data: &[u8]) -> Result<()> {
+        let handle = self.handles.get(fh, ino)?;
+        if !handle.write {
+            return Err(Error::BadFileHandle);
+        }
+        self.check_writable()?;
+        self.drop_stale().await;
+
+        let _guard = self.inode_locks.lock(ino).await;
         if let Some(attr) = self.inode_tree.get(ino).await? {
-            let (new_size, mtime) = self.cache.write_file(attr.remote_id, offset, data).await?;
+            let chunk_size = attr.chunk_size;
+            // Appends go to the end whatever offset the kernel computed.
+            let offset = if handle.append { attr.size } else { offset };
+
+            let mut written = 0;
+            while written < data.len() {
+                let pos = offset + written as u64;
+                let idx = pos / chunk_size;
+                let chunk_offset = pos - idx * chunk_size;
+                let len = ((chunk_size - chunk_offset) as usize).min(data.len() - written);
+
+                let file = self.open_chunk(ino, idx, &attr.name).await?;
+                file.write(chunk_offset, &data[written..written + len])
+                    .await?;
+                written += len;
+            }
 
+            let new_size = attr.size.max(offset + data.len() as u64);
+            let mtime = unix_time(SystemTime::now());
             self.inode_tree.update_attr(ino, new_size, mtime).await?;
 
             log::trace!(
@@ -296,42 +616,57 @@ impl Vfs {
                 "write_file: ino={} fh={} offset={} len={} new_size={} mtime={}",
                 ino, fh, offset, data.len(), new_size, mtime
             );
+            self.handles.mark_written(fh);
 
             Ok(())
         } else {
-            Err(Error::NotFound)
+            Err(Error::Stale)
         }
     }
 
-    pub async fn set_attr(
-        &self,
-        ino: u64,
-        size: Option<u64>,
-        mtime: Option<SystemTime>,
-    ) -> Result<InodeAttr> {
+    pub async fn set_attr(&self, ino: u64, set: SetAttr) -> Result<InodeAttr> {
+        self.check_writable()?;
+        self.drop_stale().await;
+        let _guard = self.inode_locks.lock(ino).await;
         if let Some(mut attr) = self.inode_tree.get(ino).await? {
-            match (size, mtime) {
-                (Some(new_size), _) if attr.size != new_size as u32 => {
-                    let mtime = mtime.unwrap_or_else(SystemTime::now);
-                    attr.mtime = mtime.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
-                    attr.size = new_size as u32;
-                    self.cache
-                        .truncate_file(attr.remote_id, new_size, &attr.name)
-                        .await?;
+            let now = SystemTime::now();
+            match (set.size, set.mtime) {
+                (Some(new_size), _) if attr.size != new_size => {
+                    attr.mtime = unix_time(set.mtime.unwrap_or(now));
+                    if new_size < attr.size {
+                        self.truncate_chunks(&attr, new_size).await?;
+                        // Uploaded by the last writer, or now if there is none.
+                        if !self.handles.mark_dirty(ino) {
+                            self.flush_chunks(ino, &attr.name, !self.async_flush)
+                                .await?;
+                        }
+                    }
+                    attr.size = new_size;
+                    attr.blocks = new_size.div_ceil(BLOCK_SIZE);
                 }
                 (_, Some(mtime)) => {
-                    attr.mtime = mtime.duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+                    attr.mtime = unix_time(mtime);
                 }
                 (_, None) => {}
             }
-            self.inode_tree
-                .update_attr(ino, attr.size as u64, attr.mtime)
-                .await?;
+            if let Some(atime) = set.atime {
+                attr.atime = unix_time(atime);
+            }
+            if let Some(mode) = set.mode {
+                attr.perm = (mode & 0o7777) as u16;
+            } else if set.uid.is_some() || set.gid.is_some() {
+                // Changing the owner drops the set-user-ID and set-group-ID bits.
+                attr.perm &= !((libc::S_ISUID | libc::S_ISGID) as u16);
+            }
+            attr.uid = set.uid.unwrap_or(attr.uid);
+            attr.gid = set.gid.unwrap_or(attr.gid);
+            attr.ctime = unix_time(now);
+            self.inode_tree.set_attr(&attr).await?;
 
             log::trace!(
                 target: "vfs::file",
-                "truncate_file: ino={} new_size={:?} new_mtime={:?} ret_attr={:?}",
-                ino, size, mtime, attr,
+                "set_attr: ino={} set={:?} ret_attr={:?}",
+                ino, set, attr,
             );
 
             Ok(attr)
@@ -342,7 +677,7 @@ impl Vfs {
 
     pub async fn sync_file(&self, ino: u64) -> Result<()> {
         if let Some(attr) = self.inode_tree.get(ino).await? {
-            self.cache.flush(attr.remote_id, &attr.name, true).await?;
+            self.flush_chunks(ino, &attr.name, true).await?;
             log::trace!(target: "vfs::file", "sync_file: ino={}", ino);
 
             Ok(())
@@ -351,24 +686,105 @@ impl Vfs {
         }
     }
 
+    /// Upload metadata changes, or pick up those of other devices.
+    pub async fn sync_metadata(&self) -> Result<()> {
+        self.inode_tree.sync().await?;
+        log::trace!(target: "vfs::inode", "sync_metadata");
+
+        Ok(())
+    }
+
     pub async fn destroy(&self) -> Result<()> {
         self.inode_tree.destroy().await?;
         Ok(())
     }
 
-    async fn get_chat(client: Client, chat_id: Option<i64>) -> Result<Option<Chat>> {
-        if let Some(id) = chat_id {
-            let mut dialogs = client.clone().iter_dialogs();
-            while let Some(dialog) = dialogs.next().await? {
-                if dialog.chat().id() == id {
-                    return Ok(Some(dialog.chat().clone()));
-                }
-            }
+    /// Refuse changes on a read-only mount, or once they could never be
+    /// uploaded because of changes from another device.
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only || self.inode_tree.is_diverged() {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Delete an inode without links once the kernel holds no reference to
+    /// it, along with its remote chunks.
+    ///
+    /// A read-only mount leaves it to a writable one, deleting it would
+    /// change the metadata and the chat.
+    async fn release(&self, ino: u64) -> Result<()> {
+        if self.read_only || self.handles.is_referenced(ino) {
+            return Ok(());
+        }
+
+        for remote_id in self.inode_tree.purge(ino).await? {
+            self.cache.delete(remote_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Drop the cache of chunks changed by other devices, before it is used.
+    async fn drop_stale(&self) {
+        for remote_id in self.inode_tree.take_stale() {
+            self.cache.invalidate(remote_id).await;
+        }
+    }
+
+    /// Get the cache of chunk `idx` of a file, creating the chunk if missing.
+    async fn open_chunk(&self, ino: u64, idx: u64, name: &str) -> Result<Arc<FileCache>> {
+        let _guard = self.chunk_locks.lock((ino, idx)).await;
+
+        if let Some(remote_id) = self.inode_tree.get_chunk(ino, idx).await? {
+            self.cache.open(remote_id).await
         } else {
-            let me = client.get_me().await?;
-            return Ok(Some(Chat::User(me)));
+            let (file, remote_id) = self.cache.open_create_empty(name).await?;
+            self.inode_tree.add_chunk(ino, idx, remote_id).await?;
+            log::debug!("Created chunk {} of {} as {}", idx, ino, remote_id);
+            Ok(file)
+        }
+    }
+
+    async fn truncate_chunks(&self, attr: &InodeAttr, new_size: u64) -> Result<()> {
+        let ino = attr.ino;
+        let chunk_size = attr.chunk_size;
+
+        // The first chunk is kept even when empty, so truncating a file to
+        // zero replaces its content rather than its message.
+        let keep = new_size.div_ceil(chunk_size).max(1);
+        for remote_id in self.inode_tree.truncate_chunks(ino, keep).await? {
+            self.cache.delete(remote_id).await?;
+        }
+
+        let tail = new_size - (keep - 1) * chunk_size;
+        if tail < chunk_size {
+            if let Some(remote_id) = self.inode_tree.get_chunk(ino, keep - 1).await? {
+                self.cache
+                    .truncate_file(remote_id, tail, &attr.name)
+                    .await?;
+            }
         }
 
-        Ok(None)
+        Ok(())
+    }
+
+    async fn flush_chunks(&self, ino: u64, name: &str, block: bool) -> Result<()> {
+        for remote_id in self.inode_tree.chunks(ino).await? {
+            if self.cache.get(&remote_id).is_some() {
+                self.cache.flush(remote_id, name, block).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn check_xattr_size(data: Vec<u8>, size: u32) -> Result<Vec<u8>> {
+    if size != 0 && data.len() > size as usize {
+        Err(Error::OutOfRange)
+    } else {
+        Ok(data)
     }
 }
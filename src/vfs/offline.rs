@@ -0,0 +1,262 @@
+//! Utilities working on a metadata database while it is not mounted.
+
+use crate::vfs::changelog;
+use crate::vfs::inode::{convert_file_type, DB_VERSION};
+
+use anyhow::Context as _;
+use fuser::FileType;
+use sqlx::{sqlite::SqliteConnectOptions, Connection as _, Row, SqliteConnection};
+use std::path::Path;
+
+/// A path of the tree, a file with several links has one for each.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub ino: u64,
+    /// Absolute path, `/` for the root.
+    pub path: String,
+    pub kind: FileType,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Status {
+    /// Schema version, older ones are migrated at the next mount.
+    pub version: u32,
+    pub inodes: u64,
+    pub bytes: u64,
+    /// Changes not uploaded yet.
+    pub pending: u64,
+}
+
+async fn open(db_path: &Path) -> anyhow::Result<SqliteConnection> {
+    let options = SqliteConnectOptions::new()
+        .filename(db_path)
+        .read_only(true);
+    SqliteConnection::connect_with(&options)
+        .await
+        .with_context(|| format!("Failed to open {}", db_path.display()))
+}
+
+pub async fn status(db_path: &Path) -> anyhow::Result<Status> {
+    let mut conn = open(db_path).await?;
+
+    let version = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut conn)
+        .await?;
+    let row = sqlx::query("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM node")
+        .fetch_one(&mut conn)
+        .await?;
+    let pending: i64 = match changelog::local_state(db_path).await? {
+        Some(state) => {
+            sqlx::query_scalar("SELECT COUNT(*) FROM change_log WHERE seq>$1")
+                .bind(state.synced)
+                .fetch_one(&mut conn)
+                .await?
+        }
+        None => 0,
+    };
+    conn.close().await?;
+
+    Ok(Status {
+        version,
+        inodes: row.get::<i64, _>(0) as u64,
+        bytes: row.get::<i64, _>(1) as u64,
+        pending: pending as u64,
+    })
+}
+
+/// All paths reachable from the root, in order.
+pub async fn list(db_path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let mut conn = open(db_path).await?;
+
+    let sql = "
+        WITH RECURSIVE tree(ino, path) AS (
+            SELECT 1, ''
+            UNION ALL
+            SELECT child_ino, tree.path || '/' || name
+            FROM node_tree
+            JOIN tree ON parent_ino=tree.ino
+        )
+        SELECT tree.ino, tree.path, node.kind, node.size
+        FROM tree
+        JOIN node ON node.ino=tree.ino
+        ORDER BY tree.path
+    ";
+    let rows = sqlx::query(sql).fetch_all(&mut conn).await?;
+    conn.close().await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let path: String = row.get(1);
+            Entry {
+                ino: row.get::<i64, _>(0) as u64,
+                path: if path.is_empty() { "/".into() } else { path },
+                kind: convert_file_type(row.get(2)),
+                size: row.get::<i64, _>(3) as u64,
+            }
+        })
+        .collect())
+}
+
+/// Check the database for corruption and inconsistent tables, returning a
+/// description of each problem found.
+///
+/// Inodes without links are not a problem: they are files removed while
+/// open, released at the next mount.
+pub async fn check(db_path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut conn = open(db_path).await?;
+    let mut problems = Vec::new();
+
+    let integrity: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(&mut conn)
+        .await?;
+    if integrity != ["ok"] {
+        // The tables cannot be trusted any further.
+        problems.extend(integrity);
+        conn.close().await?;
+        return Ok(problems);
+    }
+
+    let version: u32 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&mut conn)
+        .await?;
+    if version > DB_VERSION {
+        problems.push(format!(
+            "Schema version {} is newer than the supported {}",
+            version, DB_VERSION
+        ));
+        conn.close().await?;
+        return Ok(problems);
+    }
+
+    let sql = "
+        SELECT parent_ino, name, child_ino
+        FROM node_tree
+        WHERE child_ino NOT IN (SELECT ino FROM node)
+    ";
+    for row in sqlx::query(sql).fetch_all(&mut conn).await? {
+        problems.push(format!(
+            "Entry {:?} of inode {} links to missing inode {}",
+            row.get::<String, _>(1),
+            row.get::<i64, _>(0),
+            row.get::<i64, _>(2)
+        ));
+    }
+
+    let sql = "
+        SELECT parent_ino, name
+        FROM node_tree
+        WHERE parent_ino NOT IN (SELECT ino FROM node WHERE kind=$1)
+    ";
+    for row in sqlx::query(sql)
+        .bind(libc::S_IFDIR)
+        .fetch_all(&mut conn)
+        .await?
+    {
+        problems.push(format!(
+            "Entry {:?} is in inode {}, which is not a directory",
+            row.get::<String, _>(1),
+            row.get::<i64, _>(0)
+        ));
+    }
+
+    let sql = "
+        WITH RECURSIVE tree(ino) AS (
+            SELECT 1
+            UNION
+            SELECT child_ino
+            FROM node_tree
+            JOIN tree ON parent_ino=tree.ino
+        )
+        SELECT ino
+        FROM node
+        WHERE nlink>0 AND ino NOT IN tree
+    ";
+    for ino in sqlx::query_scalar::<_, i64>(sql)
+        .fetch_all(&mut conn)
+        .await?
+    {
+        problems.push(format!("Inode {} is not reachable from the root", ino));
+    }
+
+    let sql = "
+        SELECT ino, nlink, (SELECT COUNT(*) FROM node_tree WHERE child_ino=ino)
+        FROM node
+        WHERE kind!=$1 AND nlink>0
+    ";
+    for row in sqlx::query(sql)
+        .bind(libc::S_IFDIR)
+        .fetch_all(&mut conn)
+        .await?
+    {
+        let (nlink, links) = (row.get::<i64, _>(1), row.get::<i64, _>(2));
+        if nlink != links {
+            problems.push(format!(
+                "Inode {} has {} links but {} entries",
+                row.get::<i64, _>(0),
+                nlink,
+                links
+            ));
+        }
+    }
+
+    // Older schemas are checked as they are, before the mount migrates them.
+    for (table, since) in [("chunk", 1), ("xattr", 4), ("orphan", 6)] {
+        if version < since {
+            continue;
+        }
+        let sql = format!(
+            "SELECT DISTINCT ino FROM {} WHERE ino NOT IN (SELECT ino FROM node)",
+            table
+        );
+        for ino in sqlx::query_scalar::<_, i64>(&sql)
+            .fetch_all(&mut conn)
+            .await?
+        {
+            problems.push(format!("Table {} refers to missing inode {}", table, ino));
+        }
+    }
+
+    conn.close().await?;
+
+    Ok(problems)
+}
+
+/// Write a consistent copy of the database to `path`, which must not exist.
+pub async fn export(db_path: &Path, path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        anyhow::bail!("{} already exists", path.display());
+    }
+
+    let mut conn = open(db_path).await?;
+    sqlx::query("VACUUM INTO $1")
+        .bind(path.to_string_lossy())
+        .execute(&mut conn)
+        .await?;
+    conn.close().await?;
+
+    Ok(())
+}
+
+/// Replace the database with the copy at `path`, once it passes `check`.
+///
+/// The copy is kept at the next mount only if it is part of the remote
+/// history, see `MetadataSync::fetch_snapshot`.
+pub async fn import(path: &Path, db_path: &Path, force: bool) -> anyhow::Result<()> {
+    if db_path.exists() && !force {
+        anyhow::bail!("{} already exists", db_path.display());
+    }
+    let problems = check(path).await?;
+    if !problems.is_empty() {
+        anyhow::bail!("{} is damaged: {}", path.display(), problems.join("; "));
+    }
+
+    // Replace the database at once, a partial copy is never used.
+    let download = db_path.with_extension("download");
+    let _ = tokio::fs::remove_file(&download).await;
+    export(path, &download).await?;
+    tokio::fs::rename(&download, db_path).await?;
+
+    Ok(())
+}
@@ -0,0 +1,92 @@
+use crate::vfs::{Error, Result};
+
+use std::future::Future;
+use std::time::Duration;
+
+/// How often and how long to retry a failed remote operation.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub attempts: u32,
+    /// Delay before the second attempt, doubled after each failure.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 6,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Run `op` until it succeeds, fails permanently or runs out of attempts.
+    ///
+    /// `FLOOD_WAIT_X` errors are waited out for the requested time instead of
+    /// the backoff delay.
+    pub async fn run<T, F, Fut>(&self, what: &str, op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_while(what, op, Error::is_transient).await
+    }
+
+    /// Run `op`, which creates something and must not be carried out twice,
+    /// like `run` but without retrying a request left unanswered.
+    pub async fn run_at_most_once<T, F, Fut>(&self, what: &str, op: F) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        self.run_while(what, op, |err| err.is_transient() && !err.is_unanswered())
+            .await
+    }
+
+    async fn run_while<T, F, Fut>(
+        &self,
+        what: &str,
+        mut op: F,
+        retry: fn(&Error) -> bool,
+    ) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let mut delay = self.initial_delay;
+        let mut attempt = 1;
+        loop {
+            let err = match op().await {
+                Ok(v) => return Ok(v),
+                Err(err) if !retry(&err) || attempt >= self.attempts => return Err(err),
+                Err(err) => err,
+            };
+
+            let wait = match err.flood_wait() {
+                Some(wait) => {
+                    log::warn!("Flood wait of {:?} for {}", wait, what);
+                    wait
+                }
+                None => {
+                    log::warn!(
+                        "Failed to {} (attempt {}/{}), retry in {:?}: {}",
+                        what,
+                        attempt,
+                        self.attempts,
+                        delay,
+                        err,
+                    );
+                    let wait = delay;
+                    delay = (delay * 2).min(self.max_delay);
+                    wait
+                }
+            };
+            tokio::time::sleep(wait).await;
+            attempt += 1;
+        }
+    }
+}
@@ -0,0 +1,60 @@
+use crate::vfs::Result;
+
+use async_trait::async_trait;
+use tokio::io::AsyncRead;
+
+/// Metadata of a blob kept in a remote store.
+#[derive(Debug, Clone)]
+pub struct BlobInfo {
+    pub id: i32,
+    pub name: String,
+    pub caption: String,
+    pub size: u64,
+    /// Changes whenever the content is replaced.
+    pub version: i64,
+}
+
+/// Sequential reader of a blob's content.
+#[async_trait]
+pub trait BlobReader: Send {
+    /// Fetch the next chunk, or `None` when the whole blob has been read.
+    async fn next(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+/// Storage backend holding file contents and the metadata database.
+///
+/// Every blob is a named document with a text caption, addressed by an id
+/// assigned by the store on creation.
+#[async_trait]
+pub trait RemoteStore: Send + Sync {
+    /// Store a new blob of `size` bytes read from `reader`, returning its id.
+    async fn put(
+        &self,
+        caption: &str,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<i32>;
+
+    /// Replace the content and caption of an existing blob.
+    async fn replace(
+        &self,
+        id: i32,
+        caption: &str,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<()>;
+
+    /// Get metadata of a blob, or `None` if it does not exist.
+    async fn stat(&self, id: i32) -> Result<Option<BlobInfo>>;
+
+    /// Start reading the content of a blob.
+    async fn fetch(&self, id: i32) -> Result<Box<dyn BlobReader>>;
+
+    /// Delete a blob, doing nothing if it does not exist.
+    async fn delete(&self, id: i32) -> Result<()>;
+
+    /// List all blobs with exactly the given caption, oldest first.
+    async fn list(&self, caption: &str) -> Result<Vec<BlobInfo>>;
+}
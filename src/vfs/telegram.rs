@@ -0,0 +1,168 @@
+use crate::vfs::store::{BlobInfo, BlobReader, RemoteStore};
+use crate::vfs::{Error, Result};
+
+use async_trait::async_trait;
+use grammers_client::client::files::DownloadIter;
+use grammers_client::types::media::Uploaded;
+use grammers_client::types::{Chat, Media, Message};
+use grammers_client::{Client, InputMessage};
+use tokio::io::AsyncRead;
+
+/// Remote store keeping every blob as a document message in a Telegram chat.
+pub struct TelegramStore {
+    client: Client,
+    chat: Chat,
+}
+
+impl TelegramStore {
+    /// Use the chat with `chat_id`, or "Saved Messages" if not given.
+    pub async fn new(client: Client, chat_id: Option<i64>) -> anyhow::Result<Self> {
+        if let Some(chat) = Self::get_chat(&client, chat_id).await? {
+            Ok(Self { client, chat })
+        } else {
+            anyhow::bail!("Chat not found");
+        }
+    }
+
+    async fn get_chat(client: &Client, chat_id: Option<i64>) -> Result<Option<Chat>> {
+        if let Some(id) = chat_id {
+            let mut dialogs = client.iter_dialogs();
+            while let Some(dialog) = dialogs.next().await? {
+                if dialog.chat().id() == id {
+                    return Ok(Some(dialog.chat().clone()));
+                }
+            }
+        } else {
+            let me = client.get_me().await?;
+            return Ok(Some(Chat::User(me)));
+        }
+
+        Ok(None)
+    }
+
+    async fn upload(
+        &self,
+        name: &str,
+        mut reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<Uploaded> {
+        // Telegram refuses empty documents, store a single zero byte instead.
+        let uploaded = if size == 0 {
+            let mut stream = std::io::Cursor::new([0u8]);
+            self.client
+                .upload_stream(&mut stream, 1, String::from(name))
+                .await?
+        } else {
+            self.client
+                .upload_stream(&mut reader, size, String::from(name))
+                .await?
+        };
+
+        Ok(uploaded)
+    }
+
+    async fn get_message(&self, id: i32) -> Result<Option<Message>> {
+        let msgs = self.client.get_messages_by_id(&self.chat, &[id]).await?;
+
+        Ok(msgs.into_iter().next().flatten())
+    }
+}
+
+fn blob_info(msg: &Message) -> Result<BlobInfo> {
+    if let Some(Media::Document(document)) = msg.media() {
+        Ok(BlobInfo {
+            id: msg.id(),
+            name: String::from(document.name()),
+            caption: String::from(msg.text()),
+            size: document.size() as u64,
+            version: document.id(),
+        })
+    } else {
+        Err(Error::MediaInvalid)
+    }
+}
+
+struct TelegramBlobReader {
+    iter: DownloadIter,
+}
+
+#[async_trait]
+impl BlobReader for TelegramBlobReader {
+    async fn next(&mut self) -> Result<Option<Vec<u8>>> {
+        Ok(self.iter.next().await?)
+    }
+}
+
+#[async_trait]
+impl RemoteStore for TelegramStore {
+    async fn put(
+        &self,
+        caption: &str,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<i32> {
+        let uploaded = self.upload(name, reader, size).await?;
+        let msg = self
+            .client
+            .send_message(&self.chat, InputMessage::text(caption).file(uploaded))
+            .await?;
+
+        Ok(msg.id())
+    }
+
+    async fn replace(
+        &self,
+        id: i32,
+        caption: &str,
+        name: &str,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+        size: usize,
+    ) -> Result<()> {
+        let uploaded = self.upload(name, reader, size).await?;
+        self.client
+            .edit_message(&self.chat, id, InputMessage::text(caption).file(uploaded))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn stat(&self, id: i32) -> Result<Option<BlobInfo>> {
+        match self.get_message(id).await? {
+            Some(msg) => Ok(Some(blob_info(&msg)?)),
+            None => Ok(None),
+        }
+    }
+
+    async fn fetch(&self, id: i32) -> Result<Box<dyn BlobReader>> {
+        let msg = self.get_message(id).await?.ok_or(Error::NotFound)?;
+        match msg.media() {
+            Some(media @ Media::Document(_)) => Ok(Box::new(TelegramBlobReader {
+                iter: self.client.iter_download(&media),
+            })),
+            _ => Err(Error::MediaInvalid),
+        }
+    }
+
+    async fn delete(&self, id: i32) -> Result<()> {
+        self.client.delete_messages(&self.chat, &[id]).await?;
+
+        Ok(())
+    }
+
+    async fn list(&self, caption: &str) -> Result<Vec<BlobInfo>> {
+        let mut blobs = Vec::new();
+        let mut messages = self.client.search_messages(&self.chat).query(caption);
+        while let Some(message) = messages.next().await? {
+            if message.text() == caption {
+                if let Some(Media::Document(_)) = message.media() {
+                    blobs.push(blob_info(&message)?);
+                }
+            }
+        }
+        // Search results come newest first.
+        blobs.reverse();
+
+        Ok(blobs)
+    }
+}
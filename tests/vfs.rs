@@ -0,0 +1,1819 @@
+use std::ffi::OsStr;
+use std::io::Cursor;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use sqlx::sqlite::SqliteConnectOptions;
+use sqlx::SqlitePool;
+use telegram_fuse::vfs::{
+    offline, Config, Error, MemoryStore, RemoteStore, RetryPolicy, SetAttr, Vfs,
+};
+use tempfile::TempDir;
+
+const ROOT_INO: u64 = 1;
+const CREATE_FLAGS: i32 = libc::O_CREAT | libc::O_EXCL | libc::O_WRONLY;
+const DB_TITLE: &str = "telegram-fuse db";
+const LOG_TITLE: &str = "telegram-fuse db log";
+
+struct Fixture {
+    store: Arc<MemoryStore>,
+    dir: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        Self {
+            store: Arc::new(MemoryStore::new()),
+            dir: tempfile::tempdir().unwrap(),
+        }
+    }
+
+    /// Mount a new `Vfs` on the shared store, with its own local database.
+    async fn mount(&self, name: &str) -> Arc<Vfs> {
+        self.mount_with(name, Config::default()).await
+    }
+
+    async fn mount_with(&self, name: &str, config: Config) -> Arc<Vfs> {
+        self.try_mount_with(name, config).await.unwrap()
+    }
+
+    async fn try_mount_with(&self, name: &str, config: Config) -> anyhow::Result<Arc<Vfs>> {
+        let config = Config {
+            db_path: self.dir.path().join(name),
+            cache_dir: self.dir.path().join(format!("{}.cache", name)),
+            ..config
+        };
+        Vfs::new(self.store.clone(), config).await
+    }
+
+    /// Total size of the cached contents of the mount `name`.
+    fn cached_bytes(&self, name: &str) -> u64 {
+        std::fs::read_dir(self.dir.path().join(format!("{}.cache", name)))
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| !entry.file_name().to_str().unwrap().starts_with("queue.db"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    }
+
+    /// Unmount `vfs` and mount the store again, dropping all local state.
+    async fn remount(&self, vfs: Arc<Vfs>, name: &str) -> Arc<Vfs> {
+        vfs.destroy().await.unwrap();
+        self.mount(name).await
+    }
+}
+
+fn fast_retry(attempts: u32) -> Config {
+    Config {
+        upload_retry: RetryPolicy {
+            attempts,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+        },
+        ..Default::default()
+    }
+}
+
+fn small_chunks() -> Config {
+    Config {
+        chunk_size: 4,
+        ..Default::default()
+    }
+}
+
+/// Create a file holding `data`, then drop the entry like the kernel would.
+async fn create_file(vfs: &Vfs, parent_ino: u64, name: &str, data: &[u8]) -> u64 {
+    let (attr, fh) = vfs
+        .open_create_file(parent_ino, OsStr::new(name), 0o644, 0, 0, CREATE_FLAGS)
+        .await
+        .unwrap();
+    let ino = attr.ino;
+    vfs.write_file(ino, fh, 0, data).await.unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+    vfs.forget(ino, 1).await.unwrap();
+    ino
+}
+
+/// Write `data` at `offset` through a new handle.
+async fn write_at(vfs: &Vfs, ino: u64, offset: u64, data: &[u8]) -> Result<(), Error> {
+    let fh = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    vfs.write_file(ino, fh, offset, data).await.unwrap();
+    vfs.close_file(ino, fh).await
+}
+
+async fn read_all(vfs: &Vfs, ino: u64) -> Vec<u8> {
+    let fh = vfs.open_file(ino, libc::O_RDONLY).await.unwrap();
+    let attr = vfs.get_attr(ino).await.unwrap();
+    let data = vfs
+        .read_file(ino, fh, 0, attr.size as usize + 1)
+        .await
+        .unwrap();
+    let data = data.as_ref().to_vec();
+    vfs.close_file(ino, fh).await.unwrap();
+    data
+}
+
+#[tokio::test]
+async fn create_write_close() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "hello.txt", b"hello world").await;
+
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("hello.txt")).await.unwrap();
+    assert_eq!(attr.ino, ino);
+    assert_eq!(attr.size, 11);
+    assert_eq!(fixture.store.len(), 1);
+    assert_eq!(read_all(&vfs, ino).await, b"hello world");
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"hello world");
+}
+
+#[tokio::test]
+async fn upload_multiple_pieces() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let data: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+    let ino = create_file(&vfs, ROOT_INO, "big", &data).await;
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, data);
+}
+
+#[tokio::test]
+async fn upload_retry() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", fast_retry(3)).await;
+
+    fixture.store.fail_uploads(2);
+    let ino = create_file(&vfs, ROOT_INO, "f", b"retried").await;
+    fixture.store.fail_uploads(2);
+    write_at(&vfs, ino, 0, b"R").await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"Retried");
+}
+
+#[tokio::test]
+async fn upload_flood_wait_honoured() {
+    let fixture = Fixture::new();
+    let config = Config {
+        upload_retry: RetryPolicy {
+            attempts: 2,
+            initial_delay: Duration::from_secs(3600),
+            max_delay: Duration::from_secs(3600),
+        },
+        ..Default::default()
+    };
+    let vfs = fixture.mount_with("fuse.db", config).await;
+
+    // Waits the requested 0 seconds instead of the backoff delay.
+    fixture.store.fail_uploads_rpc(1, 420, "FLOOD_WAIT_0");
+    let created = create_file(&vfs, ROOT_INO, "f", b"data");
+    let ino = tokio::time::timeout(Duration::from_secs(10), created)
+        .await
+        .unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"data");
+}
+
+#[tokio::test]
+async fn upload_permanent_error_not_retried() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", fast_retry(3)).await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"old").await;
+    fixture.store.fail_uploads_rpc(1, 400, "FILE_PARTS_INVALID");
+    assert!(matches!(
+        write_at(&vfs, ino, 0, b"new").await,
+        Err(Error::UploadFailed)
+    ));
+}
+
+#[tokio::test]
+async fn unanswered_upload_not_repeated() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", fast_retry(3)).await;
+
+    let (attr, fh) = vfs
+        .open_create_file(ROOT_INO, OsStr::new("f"), 0o644, 0, 0, CREATE_FLAGS)
+        .await
+        .unwrap();
+    // The first chunk may have been sent, sending it again could leave two.
+    fixture.store.drop_upload_answers(1);
+    assert!(vfs.write_file(attr.ino, fh, 0, b"data").await.is_err());
+    assert_eq!(fixture.store.len(), 1);
+}
+
+#[tokio::test]
+async fn upload_failure_reported() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", fast_retry(2)).await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"old").await;
+    fixture.store.fail_uploads(2);
+    assert!(matches!(
+        write_at(&vfs, ino, 0, b"new").await,
+        Err(Error::UploadFailed)
+    ));
+
+    // The change is kept and uploaded by the next flush.
+    assert_eq!(read_all(&vfs, ino).await, b"new");
+    vfs.sync_file(ino).await.unwrap();
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"new");
+}
+
+#[tokio::test]
+async fn resume_upload_after_crash() {
+    let fixture = Fixture::new();
+    let vfs = fixture
+        .mount_with(
+            "fuse.db",
+            Config {
+                async_flush: true,
+                ..fast_retry(1)
+            },
+        )
+        .await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"old").await;
+    fixture.store.fail_uploads(1);
+    write_at(&vfs, ino, 0, b"new").await.unwrap();
+    // Exit without cleaning up anything.
+    std::mem::forget(vfs);
+
+    let vfs = fixture.mount("fuse.db").await;
+    vfs.sync_file(ino).await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"new");
+}
+
+#[tokio::test]
+async fn create_empty() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "empty", b"").await;
+
+    assert_eq!(vfs.get_attr(ino).await.unwrap().size, 0);
+    assert!(read_all(&vfs, ino).await.is_empty());
+    assert!(fixture.store.is_empty());
+}
+
+#[tokio::test]
+async fn create_exclusive_existing() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    create_file(&vfs, ROOT_INO, "a", b"").await;
+    let ret = vfs
+        .open_create_file(ROOT_INO, OsStr::new("a"), 0o644, 0, 0, CREATE_FLAGS)
+        .await;
+    assert!(matches!(ret, Err(Error::FileExists)));
+}
+
+#[tokio::test]
+async fn open_truncate() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", small_chunks()).await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"0123456789").await;
+    assert_eq!(fixture.store.len(), 3);
+
+    let fh = vfs
+        .open_file(ino, libc::O_WRONLY | libc::O_TRUNC)
+        .await
+        .unwrap();
+    assert_eq!(vfs.get_attr(ino).await.unwrap().size, 0);
+    vfs.write_file(ino, fh, 0, b"new").await.unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+    // The first chunk is rewritten in place.
+    assert_eq!(fixture.store.len(), 1);
+    assert_eq!(read_all(&vfs, ino).await, b"new");
+
+    // Creating without O_EXCL opens and truncates the existing file.
+    let flags = libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC;
+    let (attr, fh) = vfs
+        .open_create_file(ROOT_INO, OsStr::new("f"), 0o644, 0, 0, flags)
+        .await
+        .unwrap();
+    assert_eq!((attr.ino, attr.size), (ino, 0));
+    vfs.close_file(ino, fh).await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"");
+}
+
+#[tokio::test]
+async fn open_append() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"log").await;
+    let fh = vfs
+        .open_file(ino, libc::O_WRONLY | libc::O_APPEND)
+        .await
+        .unwrap();
+    vfs.write_file(ino, fh, 0, b" one").await.unwrap();
+    vfs.write_file(ino, fh, 0, b" two").await.unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+
+    assert_eq!(read_all(&vfs, ino).await, b"log one two");
+}
+
+#[tokio::test]
+async fn concurrent_appends() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"log").await;
+    let flags = libc::O_WRONLY | libc::O_APPEND;
+    let first = vfs.open_file(ino, flags).await.unwrap();
+    let second = vfs.open_file(ino, flags).await.unwrap();
+    let (a, b) = tokio::join!(
+        vfs.write_file(ino, first, 0, b" one"),
+        vfs.write_file(ino, second, 0, b" two"),
+    );
+    a.unwrap();
+    b.unwrap();
+    vfs.close_file(ino, first).await.unwrap();
+    vfs.close_file(ino, second).await.unwrap();
+
+    assert_eq!(read_all(&vfs, ino).await, b"log one two");
+}
+
+#[tokio::test]
+async fn open_access_mode() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"data").await;
+
+    let fh = vfs.open_file(ino, libc::O_RDONLY).await.unwrap();
+    let ret = vfs.write_file(ino, fh, 0, b"x").await;
+    assert!(matches!(ret, Err(Error::BadFileHandle)));
+    vfs.close_file(ino, fh).await.unwrap();
+
+    let fh = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    assert!(matches!(
+        vfs.read_file(ino, fh, 0, 4).await,
+        Err(Error::BadFileHandle)
+    ));
+    vfs.close_file(ino, fh).await.unwrap();
+
+    let fh = vfs.open_file(ino, libc::O_RDWR).await.unwrap();
+    vfs.write_file(ino, fh, 0, b"D").await.unwrap();
+    let data = vfs.read_file(ino, fh, 0, 4).await.unwrap();
+    assert_eq!(data.as_ref(), b"Data");
+    vfs.close_file(ino, fh).await.unwrap();
+
+    assert!(matches!(
+        vfs.close_file(ino, fh).await,
+        Err(Error::BadFileHandle)
+    ));
+}
+
+#[tokio::test]
+async fn flush_on_last_writer_close() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    // The first document of a fresh store.
+    let remote_id = 1;
+    let ino = create_file(&vfs, ROOT_INO, "f", b"old").await;
+    assert_eq!(fixture.store.data(remote_id).unwrap(), &b"old"[..]);
+
+    let first = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    let second = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    let reader = vfs.open_file(ino, libc::O_RDONLY).await.unwrap();
+    assert!(first != second && second != reader);
+
+    vfs.write_file(ino, first, 0, b"new").await.unwrap();
+    vfs.close_file(ino, first).await.unwrap();
+    vfs.close_file(ino, reader).await.unwrap();
+    assert_eq!(fixture.store.data(remote_id).unwrap(), &b"old"[..]);
+
+    vfs.close_file(ino, second).await.unwrap();
+    assert_eq!(fixture.store.data(remote_id).unwrap(), &b"new"[..]);
+}
+
+#[tokio::test]
+async fn handle_of_other_file() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"data").await;
+    let other = create_file(&vfs, ROOT_INO, "other", b"other").await;
+
+    let fh = vfs.open_file(ino, libc::O_RDONLY).await.unwrap();
+    assert!(matches!(
+        vfs.read_file(other, fh, 0, 4).await,
+        Err(Error::BadFileHandle)
+    ));
+    assert!(matches!(
+        vfs.close_file(other, fh).await,
+        Err(Error::BadFileHandle)
+    ));
+
+    let dir_fh = vfs.open_dir(ROOT_INO).await.unwrap();
+    assert!(dir_fh != fh);
+    assert!(matches!(vfs.open_dir(ino).await, Err(Error::NotADirectory)));
+    vfs.close_dir(ROOT_INO, dir_fh).await.unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+}
+
+#[tokio::test]
+async fn replaced_file_usable_while_open() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"old").await;
+    create_file(&vfs, ROOT_INO, "new", b"new").await;
+
+    let fh = vfs.open_file(ino, libc::O_RDWR).await.unwrap();
+    vfs.rename(ROOT_INO, OsStr::new("new"), ROOT_INO, OsStr::new("f"))
+        .await
+        .unwrap();
+    assert_eq!(fixture.store.len(), 2);
+
+    vfs.write_file(ino, fh, 0, b"O").await.unwrap();
+    let data = vfs.read_file(ino, fh, 0, 3).await.unwrap();
+    assert_eq!(data.as_ref(), b"Old");
+    vfs.close_file(ino, fh).await.unwrap();
+
+    assert!(matches!(vfs.get_attr(ino).await, Err(Error::NotFound)));
+    assert_eq!(fixture.store.len(), 1);
+}
+
+#[tokio::test]
+async fn unlink_while_open() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"data").await;
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+    let fh = vfs.open_file(attr.ino, libc::O_RDONLY).await.unwrap();
+    vfs.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+
+    assert!(matches!(
+        vfs.lookup(ROOT_INO, OsStr::new("f")).await,
+        Err(Error::NotFound)
+    ));
+    assert_eq!(vfs.get_attr(ino).await.unwrap().nlink, 0);
+    let data = vfs.read_file(ino, fh, 0, 4).await.unwrap();
+    assert_eq!(data.as_ref(), b"data");
+
+    // Kept until both the handle and the lookup are gone.
+    vfs.close_file(ino, fh).await.unwrap();
+    assert_eq!(fixture.store.len(), 1);
+    vfs.forget(ino, 1).await.unwrap();
+    assert!(matches!(vfs.get_attr(ino).await, Err(Error::NotFound)));
+    assert!(fixture.store.is_empty());
+}
+
+#[tokio::test]
+async fn unlinked_open_file_deleted_at_restart() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"data").await;
+    vfs.open_file(ino, libc::O_RDONLY).await.unwrap();
+    vfs.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+    assert_eq!(fixture.store.len(), 1);
+    // Exit without closing the file.
+    std::mem::forget(vfs);
+
+    let vfs = fixture.mount("fuse.db").await;
+    assert!(matches!(vfs.get_attr(ino).await, Err(Error::NotFound)));
+    assert!(fixture.store.is_empty());
+}
+
+#[tokio::test]
+async fn overwrite_range() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"0123456789").await;
+    let fh = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    vfs.write_file(ino, fh, 4, b"ab").await.unwrap();
+    vfs.write_file(ino, fh, 12, b"xy").await.unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+
+    let expect = b"0123ab6789\0\0xy";
+    let attr = vfs.get_attr(ino).await.unwrap();
+    assert_eq!(attr.size as usize, expect.len());
+    assert_eq!(read_all(&vfs, ino).await, expect);
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, expect);
+}
+
+#[tokio::test]
+async fn set_attr_truncate() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"0123456789").await;
+    let fh = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    let attr = vfs
+        .set_attr(
+            ino,
+            SetAttr {
+                size: Some(4),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(attr.size, 4);
+    vfs.close_file(ino, fh).await.unwrap();
+
+    assert_eq!(vfs.get_attr(ino).await.unwrap().size, 4);
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"0123");
+}
+
+#[tokio::test]
+async fn set_attr_mtime() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"data").await;
+    let mtime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let attr = vfs
+        .set_attr(
+            ino,
+            SetAttr {
+                mtime: Some(mtime),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(attr.mtime, 1_000_000);
+    assert_eq!(vfs.get_attr(ino).await.unwrap().mtime, 1_000_000);
+    assert_eq!(attr.size, 4);
+}
+
+#[tokio::test]
+async fn set_attr_mtime_after_2106() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    // Does not fit in 32 bits.
+    let secs = 7_258_118_400;
+    let ino = create_file(&vfs, ROOT_INO, "f", b"x").await;
+    vfs.set_attr(
+        ino,
+        SetAttr {
+            mtime: Some(UNIX_EPOCH + Duration::from_secs(secs)),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.get_attr(ino).await.unwrap();
+    assert_eq!(attr.mtime, secs as i64);
+    assert_eq!(
+        attr.get_file_attr().mtime,
+        UNIX_EPOCH + Duration::from_secs(secs)
+    );
+}
+
+#[tokio::test]
+async fn create_with_mode() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o700, 1000, 100)
+        .await
+        .unwrap();
+    let (file, fh) = vfs
+        .open_create_file(dir.ino, OsStr::new("f"), 0o640, 1000, 100, CREATE_FLAGS)
+        .await
+        .unwrap();
+    vfs.close_file(file.ino, fh).await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.get_attr(dir.ino).await.unwrap();
+    assert_eq!((attr.perm, attr.uid, attr.gid), (0o700, 1000, 100));
+    let attr = vfs.get_attr(file.ino).await.unwrap();
+    assert_eq!((attr.perm, attr.uid, attr.gid), (0o640, 1000, 100));
+}
+
+#[tokio::test]
+async fn set_attr_mode_owner_atime() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"data").await;
+    let attr = vfs
+        .set_attr(
+            ino,
+            SetAttr {
+                mode: Some(libc::S_IFREG | 0o4755),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!(attr.perm, 0o4755);
+
+    let atime = UNIX_EPOCH + Duration::from_secs(1_000_000);
+    let attr = vfs
+        .set_attr(
+            ino,
+            SetAttr {
+                uid: Some(1000),
+                gid: Some(100),
+                atime: Some(atime),
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+    assert_eq!((attr.perm, attr.uid, attr.gid), (0o755, 1000, 100));
+    assert_eq!(attr.atime, 1_000_000);
+    assert_eq!(attr.size, 4);
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.get_attr(ino).await.unwrap();
+    assert_eq!((attr.perm, attr.uid, attr.gid), (0o755, 1000, 100));
+    assert_eq!(attr.atime, 1_000_000);
+}
+
+#[tokio::test]
+async fn xattr_set_get_list_remove() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"").await;
+    let name = OsStr::new("user.tag");
+    assert!(matches!(
+        vfs.get_xattr(ino, name, 0).await,
+        Err(Error::NoAttribute)
+    ));
+
+    vfs.set_xattr(ino, name, b"blue", 0).await.unwrap();
+    vfs.set_xattr(ino, OsStr::new("security.capability"), b"\0\x01", 0)
+        .await
+        .unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(vfs.get_xattr(ino, name, 0).await.unwrap(), b"blue");
+    assert_eq!(vfs.get_xattr(ino, name, 4).await.unwrap(), b"blue");
+    assert!(matches!(
+        vfs.get_xattr(ino, name, 3).await,
+        Err(Error::OutOfRange)
+    ));
+    assert_eq!(
+        vfs.list_xattr(ino, 0).await.unwrap(),
+        b"security.capability\0user.tag\0"
+    );
+    assert!(matches!(
+        vfs.list_xattr(ino, 10).await,
+        Err(Error::OutOfRange)
+    ));
+
+    vfs.remove_xattr(ino, name).await.unwrap();
+    assert!(matches!(
+        vfs.remove_xattr(ino, name).await,
+        Err(Error::NoAttribute)
+    ));
+    assert_eq!(
+        vfs.list_xattr(ino, 0).await.unwrap(),
+        b"security.capability\0"
+    );
+}
+
+#[tokio::test]
+async fn xattr_create_replace_flags() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"").await;
+    let name = OsStr::new("user.tag");
+
+    let ret = vfs.set_xattr(ino, name, b"a", libc::XATTR_REPLACE).await;
+    assert!(matches!(ret, Err(Error::NoAttribute)));
+    vfs.set_xattr(ino, name, b"a", libc::XATTR_CREATE)
+        .await
+        .unwrap();
+    let ret = vfs.set_xattr(ino, name, b"b", libc::XATTR_CREATE).await;
+    assert!(matches!(ret, Err(Error::FileExists)));
+    vfs.set_xattr(ino, name, b"b", libc::XATTR_REPLACE)
+        .await
+        .unwrap();
+    assert_eq!(vfs.get_xattr(ino, name, 0).await.unwrap(), b"b");
+
+    // Attributes go away with the file, not with a new one reusing its name.
+    vfs.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+    let ino = create_file(&vfs, ROOT_INO, "f", b"").await;
+    assert_eq!(vfs.list_xattr(ino, 0).await.unwrap(), b"");
+}
+
+#[tokio::test]
+async fn statfs_usage() {
+    let config = Config {
+        capacity: 1 << 20,
+        ..Default::default()
+    };
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", config).await;
+
+    let empty = vfs.statfs().await.unwrap();
+    assert_eq!(empty.blocks, 2048);
+    assert_eq!(empty.free_blocks, 2048);
+    assert_eq!(empty.files, 1);
+
+    create_file(&vfs, ROOT_INO, "f", &[0; 1000]).await;
+    vfs.create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    let stat = vfs.statfs().await.unwrap();
+    assert_eq!(stat.blocks, 2048);
+    assert_eq!(stat.free_blocks, 2046);
+    assert_eq!(stat.files, 3);
+    assert_eq!(stat.free_files, empty.free_files - 2);
+}
+
+#[tokio::test]
+async fn rename_file() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    let dir_ino = dir.ino;
+    let ino = create_file(&vfs, ROOT_INO, "old", b"content").await;
+
+    vfs.rename(ROOT_INO, OsStr::new("old"), dir_ino, OsStr::new("new"))
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        vfs.lookup(ROOT_INO, OsStr::new("old")).await,
+        Err(Error::NotFound)
+    ));
+    let attr = vfs.lookup(dir_ino, OsStr::new("new")).await.unwrap();
+    assert_eq!(attr.ino, ino);
+    assert_eq!(read_all(&vfs, ino).await, b"content");
+}
+
+#[tokio::test]
+async fn rename_replace_deletes_remote() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    create_file(&vfs, ROOT_INO, "src", b"new").await;
+    create_file(&vfs, ROOT_INO, "dest", b"old").await;
+    assert_eq!(fixture.store.len(), 2);
+
+    vfs.rename(ROOT_INO, OsStr::new("src"), ROOT_INO, OsStr::new("dest"))
+        .await
+        .unwrap();
+
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("dest")).await.unwrap();
+    assert_eq!(read_all(&vfs, attr.ino).await, b"new");
+    assert_eq!(fixture.store.len(), 1);
+}
+
+#[tokio::test]
+async fn rename_dir_over_file() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    vfs.create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    create_file(&vfs, ROOT_INO, "file", b"").await;
+
+    let ret = vfs
+        .rename(ROOT_INO, OsStr::new("dir"), ROOT_INO, OsStr::new("file"))
+        .await;
+    assert!(matches!(ret, Err(Error::NotADirectory)));
+}
+
+#[tokio::test]
+async fn symlink_read_back() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let attr = vfs
+        .create_symlink(ROOT_INO, OsStr::new("link"), OsStr::new("dir/target"), 0, 0)
+        .await
+        .unwrap();
+    assert_eq!(attr.get_file_attr().kind, fuser::FileType::Symlink);
+    assert_eq!(attr.size, 10);
+    assert_eq!(fixture.store.len(), 0);
+    assert_eq!(vfs.read_link(attr.ino).await.unwrap(), "dir/target");
+
+    let ret = vfs
+        .create_symlink(ROOT_INO, OsStr::new("link"), OsStr::new("other"), 0, 0)
+        .await;
+    assert!(matches!(ret, Err(Error::FileExists)));
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("link")).await.unwrap();
+    assert_eq!(vfs.read_link(attr.ino).await.unwrap(), "dir/target");
+
+    vfs.remove_file(ROOT_INO, OsStr::new("link")).await.unwrap();
+    vfs.forget(attr.ino, 1).await.unwrap();
+    assert!(matches!(
+        vfs.read_link(attr.ino).await,
+        Err(Error::NotFound)
+    ));
+}
+
+#[tokio::test]
+async fn read_link_regular_file() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "file", b"").await;
+    assert!(matches!(
+        vfs.read_link(ino).await,
+        Err(Error::InvalidArgument)
+    ));
+}
+
+#[tokio::test]
+async fn rename_symlink_over_file() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    create_file(&vfs, ROOT_INO, "file", b"old").await;
+    let link = vfs
+        .create_symlink(ROOT_INO, OsStr::new("link"), OsStr::new("target"), 0, 0)
+        .await
+        .unwrap();
+
+    vfs.rename(ROOT_INO, OsStr::new("link"), ROOT_INO, OsStr::new("file"))
+        .await
+        .unwrap();
+
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("file")).await.unwrap();
+    assert_eq!(attr.ino, link.ino);
+    assert_eq!(vfs.read_link(attr.ino).await.unwrap(), "target");
+    assert_eq!(fixture.store.len(), 0);
+}
+
+#[tokio::test]
+async fn hard_link() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    let ino = create_file(&vfs, ROOT_INO, "file", b"content").await;
+
+    let attr = vfs.link(ino, dir.ino, OsStr::new("link")).await.unwrap();
+    assert_eq!(attr.ino, ino);
+    assert_eq!(attr.nlink, 2);
+    let ret = vfs.link(ino, dir.ino, OsStr::new("link")).await;
+    assert!(matches!(ret, Err(Error::FileExists)));
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.lookup(dir.ino, OsStr::new("link")).await.unwrap();
+    assert_eq!(attr.ino, ino);
+    assert_eq!(read_all(&vfs, ino).await, b"content");
+    let stored = fixture.store.len();
+
+    vfs.remove_file(ROOT_INO, OsStr::new("file")).await.unwrap();
+    assert_eq!(vfs.get_attr(ino).await.unwrap().nlink, 1);
+    assert_eq!(fixture.store.len(), stored);
+    assert_eq!(read_all(&vfs, ino).await, b"content");
+
+    // The last lookup keeps the inode after its last link is gone.
+    vfs.remove_file(dir.ino, OsStr::new("link")).await.unwrap();
+    assert_eq!(vfs.get_attr(ino).await.unwrap().nlink, 0);
+    assert_eq!(fixture.store.len(), stored);
+
+    vfs.forget(ino, 1).await.unwrap();
+    assert!(matches!(vfs.get_attr(ino).await, Err(Error::NotFound)));
+    assert_eq!(fixture.store.len(), stored - 1);
+}
+
+#[tokio::test]
+async fn hard_link_directory() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+
+    let ret = vfs.link(dir.ino, ROOT_INO, OsStr::new("link")).await;
+    assert!(matches!(ret, Err(Error::NotPermitted)));
+    assert!(matches!(
+        vfs.lookup(ROOT_INO, OsStr::new("link")).await,
+        Err(Error::NotFound)
+    ));
+    assert_eq!(vfs.get_attr(dir.ino).await.unwrap().nlink, 2);
+}
+
+#[tokio::test]
+async fn rename_over_own_link() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let ino = create_file(&vfs, ROOT_INO, "file", b"content").await;
+    vfs.link(ino, ROOT_INO, OsStr::new("link")).await.unwrap();
+
+    vfs.rename(ROOT_INO, OsStr::new("file"), ROOT_INO, OsStr::new("link"))
+        .await
+        .unwrap();
+
+    assert_eq!(
+        vfs.lookup(ROOT_INO, OsStr::new("file")).await.unwrap().ino,
+        ino
+    );
+    assert_eq!(
+        vfs.lookup(ROOT_INO, OsStr::new("link")).await.unwrap().ino,
+        ino
+    );
+    assert_eq!(vfs.get_attr(ino).await.unwrap().nlink, 2);
+    assert_eq!(fixture.store.len(), 1);
+}
+
+#[tokio::test]
+async fn rename_replace_linked_file() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    create_file(&vfs, ROOT_INO, "src", b"new").await;
+    let ino = create_file(&vfs, ROOT_INO, "dest", b"old").await;
+    vfs.link(ino, ROOT_INO, OsStr::new("link")).await.unwrap();
+
+    vfs.rename(ROOT_INO, OsStr::new("src"), ROOT_INO, OsStr::new("dest"))
+        .await
+        .unwrap();
+
+    assert_eq!(vfs.get_attr(ino).await.unwrap().nlink, 1);
+    assert_eq!(read_all(&vfs, ino).await, b"old");
+    assert_eq!(fixture.store.len(), 2);
+}
+
+#[tokio::test]
+async fn remove_dir() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    create_file(&vfs, dir.ino, "file", b"x").await;
+
+    let ret = vfs.remove_dir(ROOT_INO, OsStr::new("dir")).await;
+    assert!(matches!(ret, Err(Error::DirectoryNotEmpty)));
+
+    vfs.remove_file(dir.ino, OsStr::new("file")).await.unwrap();
+    vfs.remove_dir(ROOT_INO, OsStr::new("dir")).await.unwrap();
+
+    assert!(matches!(
+        vfs.lookup(ROOT_INO, OsStr::new("dir")).await,
+        Err(Error::NotFound)
+    ));
+    let fh = vfs.open_dir(ROOT_INO).await.unwrap();
+    let entries = vfs.read_dir(ROOT_INO, fh, 0).await.unwrap();
+    assert!(entries.as_ref().is_empty());
+    vfs.close_dir(ROOT_INO, fh).await.unwrap();
+}
+
+#[tokio::test]
+async fn remove_file_deletes_remote() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    create_file(&vfs, ROOT_INO, "f", b"x").await;
+    assert_eq!(fixture.store.len(), 1);
+
+    vfs.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+
+    assert!(fixture.store.is_empty());
+}
+
+#[tokio::test]
+async fn remount_from_remote_db() {
+    let fixture = Fixture::new();
+    {
+        let vfs = fixture.mount("first.db").await;
+        let dir = vfs
+            .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+            .await
+            .unwrap();
+        create_file(&vfs, dir.ino, "file", b"persisted").await;
+        vfs.destroy().await.unwrap();
+    }
+
+    // A different local database is restored from the store.
+    let vfs = fixture.mount("second.db").await;
+    let dir = vfs.lookup(ROOT_INO, OsStr::new("dir")).await.unwrap();
+    let attr = vfs.lookup(dir.ino, OsStr::new("file")).await.unwrap();
+    assert_eq!(read_all(&vfs, attr.ino).await, b"persisted");
+}
+
+fn snapshot_only() -> Config {
+    Config {
+        incremental_sync: false,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn remote_db_unchanged_when_clean() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("first.db", snapshot_only()).await;
+    create_file(&vfs, ROOT_INO, "f", b"data").await;
+    vfs.destroy().await.unwrap();
+    let db = fixture.store.list(DB_TITLE).await.unwrap().pop().unwrap();
+
+    let vfs = fixture.mount_with("second.db", snapshot_only()).await;
+    vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+    vfs.destroy().await.unwrap();
+
+    let info = fixture.store.stat(db.id).await.unwrap().unwrap();
+    assert_eq!(info.version, db.version);
+}
+
+#[tokio::test]
+async fn incremental_sync_replayed() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("first.db").await;
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    create_file(&vfs, dir.ino, "file", b"persisted").await;
+    vfs.destroy().await.unwrap();
+
+    // Only the changes are uploaded, there is no snapshot yet.
+    assert!(fixture.store.list(DB_TITLE).await.unwrap().is_empty());
+    assert_eq!(fixture.store.list(LOG_TITLE).await.unwrap().len(), 1);
+
+    let vfs = fixture.mount("second.db").await;
+    vfs.rename(dir.ino, OsStr::new("file"), ROOT_INO, OsStr::new("moved"))
+        .await
+        .unwrap();
+    vfs.destroy().await.unwrap();
+    assert_eq!(fixture.store.list(LOG_TITLE).await.unwrap().len(), 2);
+
+    let vfs = fixture.mount("third.db").await;
+    assert!(matches!(
+        vfs.lookup(dir.ino, OsStr::new("file")).await,
+        Err(Error::NotFound)
+    ));
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("moved")).await.unwrap();
+    assert_eq!(read_all(&vfs, attr.ino).await, b"persisted");
+}
+
+#[tokio::test]
+async fn repeated_changes_logged_once() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+    let ino = create_file(&vfs, ROOT_INO, "f", b"").await;
+    vfs.sync_metadata().await.unwrap();
+
+    let fh = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    for i in 0..100 {
+        vfs.write_file(ino, fh, i, b"x").await.unwrap();
+    }
+    vfs.close_file(ino, fh).await.unwrap();
+
+    // The node row and the new chunk.
+    let status = offline::status(&fixture.dir.path().join("fuse.db"))
+        .await
+        .unwrap();
+    assert_eq!(status.pending, 2);
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+    assert_eq!(read_all(&vfs, attr.ino).await, [b'x'; 100]);
+}
+
+#[tokio::test]
+async fn incremental_sync_compacted() {
+    let fixture = Fixture::new();
+    for i in 0..3 {
+        let vfs = fixture.mount(&format!("{}.db", i)).await;
+        vfs.create_dir(ROOT_INO, OsStr::new(&i.to_string()), 0o755, 0, 0)
+            .await
+            .unwrap();
+        vfs.destroy().await.unwrap();
+    }
+    assert_eq!(fixture.store.list(LOG_TITLE).await.unwrap().len(), 3);
+
+    // A change log as large as the database is replaced with a snapshot.
+    let vfs = fixture.mount("large.db").await;
+    vfs.set_xattr(ROOT_INO, OsStr::new("user.big"), &[7; 1 << 16], 0)
+        .await
+        .unwrap();
+    vfs.destroy().await.unwrap();
+    assert!(fixture.store.list(LOG_TITLE).await.unwrap().is_empty());
+    assert_eq!(fixture.store.list(DB_TITLE).await.unwrap().len(), 1);
+
+    let vfs = fixture.mount("last.db").await;
+    let fh = vfs.open_dir(ROOT_INO).await.unwrap();
+    let entries = vfs.read_dir(ROOT_INO, fh, 0).await.unwrap();
+    assert_eq!(entries.as_ref().len(), 3);
+    let value = vfs.get_xattr(ROOT_INO, OsStr::new("user.big"), 0).await;
+    assert_eq!(value.unwrap(), [7; 1 << 16]);
+}
+
+#[tokio::test]
+async fn metadata_upload_retry() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("first.db", fast_retry(2)).await;
+    create_file(&vfs, ROOT_INO, "f", b"data").await;
+    fixture.store.fail_uploads(1);
+    vfs.sync_metadata().await.unwrap();
+    std::mem::forget(vfs);
+
+    let vfs = fixture.mount("second.db").await;
+    let attr = vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+    assert_eq!(read_all(&vfs, attr.ino).await, b"data");
+}
+
+#[tokio::test]
+async fn newer_local_db_kept() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", snapshot_only()).await;
+    create_file(&vfs, ROOT_INO, "synced", b"").await;
+    vfs.destroy().await.unwrap();
+
+    let vfs = fixture.mount_with("fuse.db", snapshot_only()).await;
+    create_file(&vfs, ROOT_INO, "local", b"").await;
+    // Exit without uploading the database.
+    std::mem::forget(vfs);
+
+    let vfs = fixture.mount_with("fuse.db", snapshot_only()).await;
+    vfs.lookup(ROOT_INO, OsStr::new("synced")).await.unwrap();
+    vfs.lookup(ROOT_INO, OsStr::new("local")).await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    vfs.lookup(ROOT_INO, OsStr::new("local")).await.unwrap();
+}
+
+#[tokio::test]
+async fn older_local_db_replaced() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("first.db", snapshot_only()).await;
+    vfs.destroy().await.unwrap();
+
+    let vfs = fixture.mount_with("second.db", snapshot_only()).await;
+    create_file(&vfs, ROOT_INO, "f", b"").await;
+    vfs.destroy().await.unwrap();
+
+    let vfs = fixture.mount_with("first.db", snapshot_only()).await;
+    vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+}
+
+#[tokio::test]
+async fn diverged_local_db_refused() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("first.db").await;
+    vfs.destroy().await.unwrap();
+
+    let vfs = fixture.mount_with("first.db", snapshot_only()).await;
+    create_file(&vfs, ROOT_INO, "local", b"").await;
+    std::mem::forget(vfs);
+
+    let vfs = fixture.mount("second.db").await;
+    create_file(&vfs, ROOT_INO, "remote", b"").await;
+    vfs.destroy().await.unwrap();
+
+    assert!(fixture
+        .try_mount_with("first.db", Config::default())
+        .await
+        .is_err());
+}
+
+#[tokio::test]
+async fn remote_sql_refused() {
+    let fixture = Fixture::new();
+    let first = fixture.mount("first.db").await;
+    create_file(&first, ROOT_INO, "f", b"").await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+
+    // A change log posted to the chat by someone else.
+    let head = fixture.store.list(LOG_TITLE).await.unwrap().pop().unwrap();
+    let target = fixture.dir.path().join("attached");
+    let change = format!("ATTACH DATABASE '{}' AS x", target.display());
+    let data = format!("1000 {}\n{}\n", change.len(), change);
+    let name = format!("fuse.db.{}-1000.log", head.id);
+    fixture
+        .store
+        .put(LOG_TITLE, &name, &mut data.as_bytes(), data.len())
+        .await
+        .unwrap();
+
+    assert!(matches!(
+        second.sync_metadata().await,
+        Err(Error::MalformedLog)
+    ));
+    assert!(!target.exists());
+}
+
+#[tokio::test]
+async fn remote_changes_picked_up() {
+    let fixture = Fixture::new();
+    let first = fixture.mount("first.db").await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+
+    let dir = first
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    create_file(&first, dir.ino, "file", b"shared").await;
+    first.sync_metadata().await.unwrap();
+    second.sync_metadata().await.unwrap();
+
+    let attr = second.lookup(dir.ino, OsStr::new("file")).await.unwrap();
+    assert_eq!(read_all(&second, attr.ino).await, b"shared");
+
+    second
+        .rename(dir.ino, OsStr::new("file"), ROOT_INO, OsStr::new("moved"))
+        .await
+        .unwrap();
+    second.sync_metadata().await.unwrap();
+    first.sync_metadata().await.unwrap();
+    first.lookup(ROOT_INO, OsStr::new("moved")).await.unwrap();
+}
+
+#[tokio::test]
+async fn remote_content_change_picked_up() {
+    let fixture = Fixture::new();
+    let first = fixture.mount("first.db").await;
+    let ino = create_file(&first, ROOT_INO, "f", b"old!").await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+    assert_eq!(read_all(&second, ino).await, b"old!");
+
+    write_at(&first, ino, 0, b"NEW!").await.unwrap();
+    first.sync_metadata().await.unwrap();
+    second.sync_metadata().await.unwrap();
+    assert_eq!(read_all(&second, ino).await, b"NEW!");
+}
+
+#[tokio::test]
+async fn remote_unlinked_open_file_kept() {
+    let fixture = Fixture::new();
+    let first = fixture.mount("first.db").await;
+    let ino = create_file(&first, ROOT_INO, "f", b"open").await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+    second.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+
+    let fh = first.open_file(ino, libc::O_RDONLY).await.unwrap();
+    first.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+    first.sync_metadata().await.unwrap();
+    second.sync_metadata().await.unwrap();
+
+    // Only the device holding it open deletes it, at close or next mount.
+    let blobs = fixture.store.len();
+    second.forget(ino, 1).await.unwrap();
+    let second = fixture.remount(second, "second.db").await;
+    assert_eq!(fixture.store.len(), blobs);
+    assert_eq!(second.get_attr(ino).await.unwrap().nlink, 0);
+
+    let data = first.read_file(ino, fh, 0, 4).await.unwrap();
+    assert_eq!(data.as_ref(), b"open");
+    first.close_file(ino, fh).await.unwrap();
+    assert!(matches!(first.get_attr(ino).await, Err(Error::NotFound)));
+}
+
+#[tokio::test]
+async fn remote_snapshot_picked_up() {
+    let fixture = Fixture::new();
+    let first = fixture.mount_with("first.db", snapshot_only()).await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+
+    create_file(&first, ROOT_INO, "f", b"").await;
+    first.sync_metadata().await.unwrap();
+    second.sync_metadata().await.unwrap();
+    second.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+
+    // New inodes are numbered after those of the snapshot.
+    let attr = second
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    assert!(attr.ino > first.lookup(ROOT_INO, OsStr::new("f")).await.unwrap().ino);
+}
+
+#[tokio::test]
+async fn concurrent_changes_refused() {
+    let fixture = Fixture::new();
+    let first = fixture.mount("first.db").await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+
+    // Both change the root directory.
+    create_file(&first, ROOT_INO, "first", b"").await;
+    let ino = create_file(&second, ROOT_INO, "second", b"").await;
+    let fh = second.open_file(ino, libc::O_WRONLY).await.unwrap();
+    first.sync_metadata().await.unwrap();
+    assert!(matches!(second.sync_metadata().await, Err(Error::Conflict)));
+
+    // Further changes of the second device would be lost, they are refused.
+    assert!(matches!(
+        second
+            .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+            .await,
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        second.write_file(ino, fh, 0, b"data").await,
+        Err(Error::ReadOnly)
+    ));
+
+    // Changes of the second device are kept back.
+    let vfs = fixture.mount("third.db").await;
+    vfs.lookup(ROOT_INO, OsStr::new("first")).await.unwrap();
+    assert!(matches!(
+        vfs.lookup(ROOT_INO, OsStr::new("second")).await,
+        Err(Error::NotFound)
+    ));
+}
+
+#[tokio::test]
+async fn concurrent_changes_rebased() {
+    let fixture = Fixture::new();
+    let first = fixture.mount("first.db").await;
+    let a = create_file(&first, ROOT_INO, "a", b"a").await;
+    let b = create_file(&first, ROOT_INO, "b", b"b").await;
+    first.sync_metadata().await.unwrap();
+    let second = fixture.mount("second.db").await;
+
+    write_at(&first, a, 0, b"first").await.unwrap();
+    write_at(&second, b, 0, b"second").await.unwrap();
+    first.sync_metadata().await.unwrap();
+    // Other rows changed, the second device uploads after the first one.
+    second.sync_metadata().await.unwrap();
+    first.sync_metadata().await.unwrap();
+    assert_eq!(read_all(&first, b).await, b"second");
+    assert_eq!(read_all(&second, a).await, b"first");
+
+    let vfs = fixture.mount("third.db").await;
+    assert_eq!(read_all(&vfs, a).await, b"first");
+    assert_eq!(read_all(&vfs, b).await, b"second");
+}
+
+#[tokio::test]
+async fn read_only_mount() {
+    let fixture = Fixture::new();
+    let writer = fixture.mount("writer.db").await;
+    let ino = create_file(&writer, ROOT_INO, "f", b"archived").await;
+    writer.sync_metadata().await.unwrap();
+
+    let config = Config {
+        read_only: true,
+        ..Default::default()
+    };
+    let blobs = fixture.store.len();
+    let vfs = fixture.mount_with("reader.db", config).await;
+    assert_eq!(read_all(&vfs, ino).await, b"archived");
+
+    assert!(matches!(
+        vfs.create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+            .await,
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        vfs.open_create_file(ROOT_INO, OsStr::new("g"), 0o644, 0, 0, CREATE_FLAGS)
+            .await,
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        vfs.open_file(ino, libc::O_WRONLY).await,
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        vfs.remove_file(ROOT_INO, OsStr::new("f")).await,
+        Err(Error::ReadOnly)
+    ));
+    assert!(matches!(
+        vfs.set_xattr(ino, OsStr::new("user.a"), b"1", 0).await,
+        Err(Error::ReadOnly)
+    ));
+
+    // Changes of the writer are still picked up.
+    create_file(&writer, ROOT_INO, "g", b"new").await;
+    writer.sync_metadata().await.unwrap();
+    vfs.sync_metadata().await.unwrap();
+    vfs.lookup(ROOT_INO, OsStr::new("g")).await.unwrap();
+
+    vfs.destroy().await.unwrap();
+    // Only the new file and its change log were uploaded, by the writer.
+    assert_eq!(fixture.store.len(), blobs + 2);
+}
+
+#[tokio::test]
+async fn read_only_mount_forget() {
+    let fixture = Fixture::new();
+    let writer = fixture.mount("writer.db").await;
+    let ino = create_file(&writer, ROOT_INO, "f", b"open").await;
+    writer.sync_metadata().await.unwrap();
+
+    let config = Config {
+        read_only: true,
+        ..Default::default()
+    };
+    let vfs = fixture.mount_with("reader.db", config).await;
+    vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+
+    // Removed by the writer while open, the reader gets the change.
+    let fh = writer.open_file(ino, libc::O_RDONLY).await.unwrap();
+    writer.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+    writer.sync_metadata().await.unwrap();
+    vfs.sync_metadata().await.unwrap();
+    assert_eq!(vfs.get_attr(ino).await.unwrap().nlink, 0);
+
+    let blobs = fixture.store.len();
+    vfs.forget(ino, 1).await.unwrap();
+    assert_eq!(fixture.store.len(), blobs);
+    assert_eq!(read_all(&writer, ino).await, b"open");
+
+    // Nothing is left to upload, so changes of the writer are still picked up.
+    create_file(&writer, ROOT_INO, "g", b"").await;
+    writer.sync_metadata().await.unwrap();
+    vfs.sync_metadata().await.unwrap();
+    vfs.lookup(ROOT_INO, OsStr::new("g")).await.unwrap();
+
+    writer.close_file(ino, fh).await.unwrap();
+}
+
+#[tokio::test]
+async fn offline_list_and_check() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+    let dir = vfs
+        .create_dir(ROOT_INO, OsStr::new("dir"), 0o755, 0, 0)
+        .await
+        .unwrap();
+    let ino = create_file(&vfs, dir.ino, "f", b"hello").await;
+    vfs.link(ino, ROOT_INO, OsStr::new("g")).await.unwrap();
+    vfs.destroy().await.unwrap();
+
+    let db_path = fixture.dir.path().join("fuse.db");
+    let entries = offline::list(&db_path).await.unwrap();
+    let paths: Vec<_> = entries.iter().map(|entry| entry.path.as_str()).collect();
+    assert_eq!(paths, ["/", "/dir", "/dir/f", "/g"]);
+    assert_eq!(entries[2].ino, ino);
+    assert_eq!(entries[3].size, 5);
+    assert!(offline::check(&db_path).await.unwrap().is_empty());
+
+    let status = offline::status(&db_path).await.unwrap();
+    assert_eq!(status.inodes, 3);
+    assert_eq!(status.pending, 0);
+
+    let options = SqliteConnectOptions::new().filename(&db_path);
+    let db = SqlitePool::connect_with(options).await.unwrap();
+    sqlx::query("DELETE FROM node WHERE ino=$1")
+        .bind(dir.ino as i64)
+        .execute(&db)
+        .await
+        .unwrap();
+    db.close().await;
+
+    // The entry of the directory, and the one in it. The file is still
+    // reachable through its other link.
+    assert_eq!(offline::check(&db_path).await.unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn offline_export_import() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+    create_file(&vfs, ROOT_INO, "f", b"hello").await;
+    vfs.destroy().await.unwrap();
+
+    let db_path = fixture.dir.path().join("fuse.db");
+    let backup = fixture.dir.path().join("backup.db");
+    offline::export(&db_path, &backup).await.unwrap();
+    assert!(offline::export(&db_path, &backup).await.is_err());
+
+    let restored = fixture.dir.path().join("restored.db");
+    offline::import(&backup, &restored, false).await.unwrap();
+    assert!(offline::import(&backup, &restored, false).await.is_err());
+    offline::import(&backup, &restored, true).await.unwrap();
+
+    let vfs = fixture.mount("restored.db").await;
+    vfs.lookup(ROOT_INO, OsStr::new("f")).await.unwrap();
+}
+
+#[tokio::test]
+async fn chunked_write_read() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", small_chunks()).await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"0123456789").await;
+    assert_eq!(fixture.store.len(), 3);
+
+    let fh = vfs.open_file(ino, libc::O_RDWR).await.unwrap();
+    let data = vfs.read_file(ino, fh, 3, 6).await.unwrap();
+    assert_eq!(data.as_ref(), b"345678");
+
+    // Overwrite across a chunk boundary.
+    vfs.write_file(ino, fh, 2, b"abcd").await.unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+    assert_eq!(fixture.store.len(), 3);
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"01abcd6789");
+}
+
+#[tokio::test]
+async fn chunked_truncate() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", small_chunks()).await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"0123456789").await;
+    let fh = vfs.open_file(ino, libc::O_WRONLY).await.unwrap();
+    vfs.set_attr(
+        ino,
+        SetAttr {
+            size: Some(5),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    vfs.close_file(ino, fh).await.unwrap();
+    assert_eq!(fixture.store.len(), 2);
+
+    // Growing again reads zeros after the old end.
+    vfs.set_attr(
+        ino,
+        SetAttr {
+            size: Some(7),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(read_all(&vfs, ino).await, b"01234\0\0");
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"01234\0\0");
+}
+
+#[tokio::test]
+async fn chunked_truncate_uncached_uploaded_once() {
+    let fixture = Fixture::new();
+    let config = Config {
+        chunk_size: 4 << 20,
+        ..Default::default()
+    };
+    let vfs = fixture.mount_with("fuse.db", config).await;
+    let data = vec![b'x'; 8 << 20];
+    let ino = create_file(&vfs, ROOT_INO, "f", &data).await;
+
+    // Nothing is cached after mounting from the remote database. The last
+    // chunk is uploaded once downloaded, long enough to still be in progress
+    // when the truncated file is flushed.
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let replaces = fixture.store.replaces();
+    vfs.set_attr(
+        ino,
+        SetAttr {
+            size: Some(7 << 20),
+            ..Default::default()
+        },
+    )
+    .await
+    .unwrap();
+    assert_eq!(fixture.store.replaces(), replaces + 1);
+    assert_eq!(read_all(&vfs, ino).await, &data[..7 << 20]);
+}
+
+#[tokio::test]
+async fn chunked_sparse_write() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", small_chunks()).await;
+
+    let ino = create_file(&vfs, ROOT_INO, "f", b"01").await;
+    write_at(&vfs, ino, 10, b"xy").await.unwrap();
+
+    // Only the first and the third chunk are stored.
+    assert_eq!(fixture.store.len(), 2);
+    assert_eq!(read_all(&vfs, ino).await, b"01\0\0\0\0\0\0\0\0xy");
+}
+
+#[tokio::test]
+async fn file_larger_than_4gib() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+
+    let offset = 5 << 30;
+    let ino = create_file(&vfs, ROOT_INO, "f", b"").await;
+    write_at(&vfs, ino, offset, b"end").await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    let attr = vfs.get_attr(ino).await.unwrap();
+    assert_eq!(attr.size, offset + 3);
+    assert_eq!(attr.blocks, (offset + 3).div_ceil(512));
+    let fh = vfs.open_file(ino, libc::O_RDONLY).await.unwrap();
+    let data = vfs.read_file(ino, fh, offset - 1, 8).await.unwrap();
+    assert_eq!(data.as_ref(), b"\0end");
+}
+
+#[tokio::test]
+async fn cache_kept_across_restart() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+    let ino = create_file(&vfs, ROOT_INO, "f", b"cached").await;
+    vfs.destroy().await.unwrap();
+
+    let vfs = fixture.mount("fuse.db").await;
+    let fetches = fixture.store.fetches();
+    assert_eq!(read_all(&vfs, ino).await, b"cached");
+    assert_eq!(fixture.store.fetches(), fetches);
+
+    // A newer remote version is downloaded again.
+    let other = fixture.mount("other.db").await;
+    write_at(&other, ino, 0, b"C").await.unwrap();
+    let fetches = fixture.store.fetches();
+    let vfs = fixture.remount(vfs, "fuse.db").await;
+    assert_eq!(read_all(&vfs, ino).await, b"Cached");
+    assert!(fixture.store.fetches() > fetches);
+}
+
+#[tokio::test]
+async fn cache_size_limit() {
+    let config = Config {
+        cache_size: 10,
+        ..Default::default()
+    };
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", config).await;
+
+    let first = create_file(&vfs, ROOT_INO, "first", b"01234567").await;
+    create_file(&vfs, ROOT_INO, "second", b"89abcdef").await;
+    assert!(fixture.cached_bytes("fuse.db") <= 10);
+
+    let fetches = fixture.store.fetches();
+    assert_eq!(read_all(&vfs, first).await, b"01234567");
+    assert_eq!(fixture.store.fetches(), fetches + 1);
+}
+
+#[tokio::test]
+async fn cache_keeps_dirty_files() {
+    let config = Config {
+        cache_size: 1,
+        ..Default::default()
+    };
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", config).await;
+
+    let flags = libc::O_CREAT | libc::O_EXCL | libc::O_RDWR;
+    let (attr, fh) = vfs
+        .open_create_file(ROOT_INO, OsStr::new("dirty"), 0o644, 0, 0, flags)
+        .await
+        .unwrap();
+    vfs.write_file(attr.ino, fh, 0, b"unsaved").await.unwrap();
+    create_file(&vfs, ROOT_INO, "other", b"other").await;
+
+    let data = vfs.read_file(attr.ino, fh, 0, 7).await.unwrap();
+    assert_eq!(data.as_ref(), b"unsaved");
+    vfs.close_file(attr.ino, fh).await.unwrap();
+
+    let vfs = fixture.remount(vfs, "second.db").await;
+    assert_eq!(read_all(&vfs, attr.ino).await, b"unsaved");
+}
+
+#[tokio::test]
+async fn chunked_remove() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount_with("fuse.db", small_chunks()).await;
+
+    create_file(&vfs, ROOT_INO, "f", b"0123456789").await;
+    vfs.remove_file(ROOT_INO, OsStr::new("f")).await.unwrap();
+
+    assert!(fixture.store.is_empty());
+}
+
+#[tokio::test]
+async fn migrate_single_document_files() {
+    let fixture = Fixture::new();
+    let mut content = Cursor::new(b"legacy".to_vec());
+    let remote_id = fixture
+        .store
+        .put("legacy.txt", "legacy.txt", &mut content, 6)
+        .await
+        .unwrap();
+
+    // Metadata layout before files were split into chunks.
+    let options = SqliteConnectOptions::new()
+        .filename(fixture.dir.path().join("fuse.db"))
+        .create_if_missing(true);
+    let db = SqlitePool::connect_with(options).await.unwrap();
+    for sql in [
+        "CREATE TABLE node (
+            ino INTEGER PRIMARY KEY AUTOINCREMENT,
+            size INTEGER DEFAULT 0 NOT NULL,
+            blocks INTEGER DEFAULT 0,
+            atime INTEGER,
+            mtime INTEGER,
+            ctime INTEGER,
+            crtime INTEGER,
+            kind INTEGER,
+            perm INTEGER,
+            nlink INTEGER DEFAULT 0,
+            uid INTEGER DEFAULT 0,
+            gid INTEGER DEFAULT 0,
+            rdev INTEGER DEFAULT 0,
+            blksize INTEGER,
+            flags INTEGER DEFAULT 0,
+            remote_id INTEGER DEFAULT 0
+        )",
+        "CREATE TABLE node_tree (
+            parent_ino INTEGER,
+            child_ino INTEGER,
+            file_type INTEGER,
+            name TEXT,
+            PRIMARY KEY (parent_ino, name)
+        )",
+    ] {
+        sqlx::query(sql).execute(&db).await.unwrap();
+    }
+    sqlx::query(
+        "INSERT INTO node (ino, size, atime, mtime, ctime, crtime, kind, perm, nlink, blksize, remote_id)
+        VALUES (2, 6, 0, 0, 0, 0, $1, 420, 1, 512, $2)",
+    )
+    .bind(libc::S_IFREG)
+    .bind(remote_id)
+    .execute(&db)
+    .await
+    .unwrap();
+    sqlx::query("INSERT INTO node_tree VALUES (1, 2, $1, 'legacy.txt')")
+        .bind(libc::S_IFREG)
+        .execute(&db)
+        .await
+        .unwrap();
+    db.close().await;
+
+    // Checked offline without the tables added since.
+    offline::check(&fixture.dir.path().join("fuse.db"))
+        .await
+        .unwrap();
+
+    let vfs = fixture.mount_with("fuse.db", small_chunks()).await;
+    let attr = vfs
+        .lookup(ROOT_INO, OsStr::new("legacy.txt"))
+        .await
+        .unwrap();
+    assert_eq!(read_all(&vfs, attr.ino).await, b"legacy");
+
+    // The old document is updated in place as the first chunk.
+    write_at(&vfs, attr.ino, 0, b"L").await.unwrap();
+    assert_eq!(fixture.store.len(), 1);
+    assert_eq!(fixture.store.data(remote_id).unwrap(), &b"Legacy"[..]);
+}
+
+#[tokio::test]
+async fn newer_schema_refused() {
+    let fixture = Fixture::new();
+    let vfs = fixture.mount("fuse.db").await;
+    vfs.destroy().await.unwrap();
+
+    let db_path = fixture.dir.path().join("fuse.db");
+    let options = SqliteConnectOptions::new().filename(&db_path);
+    let db = SqlitePool::connect_with(options).await.unwrap();
+    sqlx::query("PRAGMA user_version = 1000")
+        .execute(&db)
+        .await
+        .unwrap();
+    db.close().await;
+
+    assert!(fixture
+        .try_mount_with("fuse.db", Config::default())
+        .await
+        .is_err());
+    let db = SqlitePool::connect_with(SqliteConnectOptions::new().filename(&db_path))
+        .await
+        .unwrap();
+    let version: u32 = sqlx::query_scalar("PRAGMA user_version")
+        .fetch_one(&db)
+        .await
+        .unwrap();
+    assert_eq!(version, 1000);
+}